@@ -10,12 +10,19 @@ use thiserror::Error;
 
 pub static USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 eightyeightthirtyone/1.0.0 (https://github.com/NotNite/eightyeightthirtyone)";
 
+/// The most results to report back to the server in a single request
+const MAX_SUBMIT_BATCH: usize = 50;
+
+/// How many times to try reporting a batch of results before giving up on it
+const SUBMIT_ATTEMPTS: usize = 3;
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     host: String,
     key: String,
     drivers: Option<Vec<String>>,
     tasks: Option<usize>,
+    prefetch: Option<usize>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -31,6 +38,30 @@ pub struct WorkSchema {
     pub result_url: String,
     pub success: bool,
     pub links: Option<Vec<LinkSchema>>,
+    pub crawl_delay: Option<f32>,
+    /// robots.txt doesn't let us scrape the page - kept apart from failures so the server can honor it
+    pub disallowed: Option<bool>,
+    /// Why the page couldn't be scraped, when it couldn't
+    pub failure: Option<FailureReason>,
+    /// The status the page responded with, for `FailureReason::HttpStatus`
+    pub http_status: Option<u16>,
+    /// Lets the server recognise a result it has already applied when a submit is retried
+    pub submission_id: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    Dns,
+    Timeout,
+    HttpStatus,
+    NotHtml,
+    Parse,
+    Webdriver,
+}
+
+fn submission_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
 }
 
 #[derive(Error, Debug)]
@@ -39,15 +70,71 @@ pub enum ScrapeError {
     WebDriver(#[from] thirtyfour::error::WebDriverError),
 
     #[error("API call error")]
-    Api(#[from] Option<reqwest::Error>),
+    Api(#[source] Option<reqwest::Error>),
 
     #[error("robots.txt disallowed")]
     Robots,
 
+    #[error("request failed")]
+    Request(#[from] reqwest::Error),
+
+    #[error("page returned {0}")]
+    HttpStatus(u16),
+
+    #[error("page isn't HTML")]
+    NotHtml,
+
+    #[error("couldn't parse page")]
+    Parse(#[from] url::ParseError),
+
+    #[error("rate limited for {0:?}")]
+    RateLimited(Duration),
+
     #[error("unknown error")]
     Unknown(#[from] anyhow::Error),
 }
 
+impl ScrapeError {
+    /// What to tell the server about why the page failed, if we can tell
+    fn failure(&self) -> (Option<FailureReason>, Option<u16>) {
+        let reason = match self {
+            Self::HttpStatus(status) => return (Some(FailureReason::HttpStatus), Some(*status)),
+            Self::NotHtml => FailureReason::NotHtml,
+            Self::Parse(_) => FailureReason::Parse,
+            Self::WebDriver(_) => FailureReason::Webdriver,
+            Self::Request(e) if e.is_timeout() => FailureReason::Timeout,
+            Self::Request(e) if e.is_connect() && is_dns_error(e) => FailureReason::Dns,
+            _ => return (None, None),
+        };
+        (Some(reason), None)
+    }
+}
+
+/// reqwest doesn't expose DNS failures directly, so look for hyper's resolver error in the chain
+fn is_dns_error(e: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        if e.to_string().starts_with("dns error") {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Whether a response claims to be a web page - missing content types are given the benefit of the doubt
+fn is_html(response: &reqwest::Response) -> bool {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| {
+            let x = x.to_ascii_lowercase();
+            x.starts_with("text/html") || x.starts_with("application/xhtml+xml")
+        })
+        .unwrap_or(true)
+}
+
 #[async_recursion::async_recursion]
 async fn recursive_children(el: &WebElement) -> anyhow::Result<Vec<WebElement>> {
     let mut children = vec![];
@@ -84,7 +171,7 @@ async fn image_is_88x31(
     let hash = format!("{:x}", sha2::Sha256::digest(&bytes));
 
     reqwest_client
-        .post(&format!("{}/badge/{}", config.host, hash))
+        .post(format!("{}/badge/{}", config.host, hash))
         .header("Authorization", &format!("Bearer {}", config.key))
         .body(bytes)
         .send()
@@ -94,12 +181,12 @@ async fn image_is_88x31(
     Ok(hash)
 }
 
-async fn check_robots_txt(url: &str) -> anyhow::Result<bool> {
+async fn fetch_robots_txt(url: &str) -> Result<Robot, ScrapeError> {
     let robot_url = get_robots_url(url)?;
     let response = reqwest::get(robot_url).await?;
     let text = response.text().await?;
     let robots = Robot::new(USER_AGENT, text.as_bytes())?;
-    Ok(robots.allowed(url))
+    Ok(robots)
 }
 
 async fn process(
@@ -108,11 +195,16 @@ async fn process(
     reqwest_client: &reqwest::Client,
     config: &Config,
 ) -> Result<WorkSchema, ScrapeError> {
-    if !check_robots_txt(url).await.unwrap_or_default() {
+    // Not being able to fetch robots.txt is a failure like any other, rather than a disallow
+    let robots = fetch_robots_txt(url).await?;
+    if !robots.allowed(url) {
         return Err(ScrapeError::Robots);
     }
 
-    let parsed_url = url::Url::parse(url).map_err(|e| ScrapeError::Unknown(e.into()))?;
+    // Passed along so the server can space out requests to this domain
+    let crawl_delay = robots.delay;
+
+    let parsed_url = url::Url::parse(url)?;
     let mut result = Vec::new();
     let mut current_url = parsed_url.to_string();
 
@@ -188,10 +280,7 @@ async fn process(
                     for child in children {
                         if child.tag_name().await? == "img" {
                             if let Some(src) = child.attr("src").await? {
-                                let src = parsed_url
-                                    .join(&src)
-                                    .map_err(|e| ScrapeError::Unknown(e.into()))?
-                                    .to_string();
+                                let src = parsed_url.join(&src)?.to_string();
 
                                 if let Ok(hash) = image_is_88x31(&src, reqwest_client, config).await
                                 {
@@ -213,6 +302,12 @@ async fn process(
             let document = loop {
                 let response = reqwest_client.get(current_url).send().await?;
                 current_url = response.url().to_string();
+                if !response.status().is_success() {
+                    return Err(ScrapeError::HttpStatus(response.status().as_u16()));
+                }
+                if !is_html(&response) {
+                    return Err(ScrapeError::NotHtml);
+                }
                 let text = response.text().await?;
 
                 let document = scraper::Html::parse_document(&text);
@@ -286,10 +381,7 @@ async fn process(
                         for child in children {
                             if let Some(elem) = child.value().as_element() {
                                 if let Some(src) = elem.attr("src") {
-                                    let src = parsed_url
-                                        .join(src)
-                                        .map_err(|e| ScrapeError::Unknown(e.into()))?
-                                        .to_string();
+                                    let src = parsed_url.join(src)?.to_string();
 
                                     queued_images.push((real_href.clone(), src));
                                 }
@@ -316,70 +408,202 @@ async fn process(
         result_url: current_url.to_string(),
         success: true,
         links: Some(result),
+        crawl_delay,
+        disallowed: None,
+        failure: None,
+        http_status: None,
+        submission_id: Some(submission_id()),
     })
 }
 
+/// Scrapes a single URL and queues the result to be reported back to the server
 async fn try_work(
     client: &reqwest::Client,
     driver: &Option<WebDriver>,
     config: &Config,
+    url: &str,
+    results: &flume::Sender<WorkSchema>,
 ) -> Result<(), ScrapeError> {
+    println!("Processing: {}", url);
+    let result = process(driver, url, client, config).await;
+    match result {
+        Ok(work) => {
+            results.send(work).ok();
+            Ok(())
+        }
+        Err(ScrapeError::Robots) => {
+            println!("Disallowed by robots.txt: {}", url);
+            results
+                .send(WorkSchema {
+                    orig_url: url.to_string(),
+                    result_url: url.to_string(),
+                    success: false,
+                    links: None,
+                    crawl_delay: None,
+                    disallowed: Some(true),
+                    failure: None,
+                    http_status: None,
+                    submission_id: Some(submission_id()),
+                })
+                .ok();
+
+            Ok(())
+        }
+        Err(e) => {
+            // Do not report webdriver errors as a failure - sometimes they crash
+            if let ScrapeError::WebDriver(WebDriverError::CmdError(_)) = e {
+                return Err(e);
+            }
+
+            let (failure, http_status) = e.failure();
+            results
+                .send(WorkSchema {
+                    orig_url: url.to_string(),
+                    result_url: url.to_string(),
+                    success: false,
+                    links: None,
+                    crawl_delay: None,
+                    disallowed: None,
+                    failure,
+                    http_status,
+                    submission_id: Some(submission_id()),
+                })
+                .ok();
+
+            Err(e)
+        }
+    }
+}
+
+/// Turns a 429 from the server into an error carrying how long to back off for.
+///
+/// A 503 with a Retry-After (sent while a retried submission is still being applied) is
+/// treated the same way, so it's waited out rather than given up on.
+fn check_rate_limit(response: &reqwest::Response) -> Result<(), ScrapeError> {
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok());
+
+    let retry_after = match response.status() {
+        reqwest::StatusCode::TOO_MANY_REQUESTS => retry_after.unwrap_or(60),
+        reqwest::StatusCode::SERVICE_UNAVAILABLE if retry_after.is_some() => {
+            retry_after.unwrap_or_default()
+        }
+        _ => return Ok(()),
+    };
+    Err(ScrapeError::RateLimited(Duration::from_secs(retry_after)))
+}
+
+async fn fetch_work(
+    client: &reqwest::Client,
+    config: &Config,
+    count: usize,
+) -> Result<Vec<String>, ScrapeError> {
     let req = client
-        .get(&format!("{}/work", config.host))
+        .get(format!("{}/work", config.host))
+        .query(&[("count", count)])
         .header("Authorization", format!("Bearer {}", config.key.clone()))
         .send()
         .await
         .map_err(|e| ScrapeError::Api(Some(e)))?;
 
+    check_rate_limit(&req)?;
     if !req.status().is_success() {
         return Err(ScrapeError::Api(None));
     }
 
-    let url = req.text().await?;
-
-    if !url.is_empty() {
-        println!("Processing: {}", url);
-        let result = process(driver, &url, client, config).await;
-        if let Ok(work) = result {
-            let work = serde_json::to_string(&work).map_err(|e| ScrapeError::Unknown(e.into()))?;
-            client
-                .post(&format!("{}/work", config.host))
-                .header("Content-Type", "application/json")
-                .header("Authorization", format!("Bearer {}", config.key.clone()))
-                .body(work)
-                .send()
-                .await
-                .map_err(|e| ScrapeError::Api(Some(e)))?;
-        } else {
-            // Do not report webdriver errors as a failure - sometimes they crash
-            if let Err(ScrapeError::WebDriver(WebDriverError::CmdError(_))) = result {
-                return Err(result.err().unwrap());
-            }
+    let text = req.text().await.map_err(|e| ScrapeError::Api(Some(e)))?;
+    let work = serde_json::from_str(&text).map_err(|e| ScrapeError::Unknown(e.into()))?;
+    Ok(work)
+}
 
-            client
-                .post(&format!("{}/work", config.host))
-                .header("Content-Type", "application/json")
-                .header("Authorization", format!("Bearer {}", config.key.clone()))
-                .body(
-                    serde_json::to_string(&WorkSchema {
-                        orig_url: url.to_string(),
-                        result_url: url.to_string(),
-                        success: false,
-                        links: None,
-                    })
-                    .map_err(|e| ScrapeError::Unknown(e.into()))?,
-                )
-                .send()
-                .await
-                .map_err(|e| ScrapeError::Api(Some(e)))?;
-
-            return Err(result.err().unwrap());
-        }
+async fn submit_work(
+    client: &reqwest::Client,
+    config: &Config,
+    work: &[WorkSchema],
+) -> Result<(), ScrapeError> {
+    let work = serde_json::to_string(work).map_err(|e| ScrapeError::Unknown(e.into()))?;
+    let req = client
+        .post(format!("{}/work/batch", config.host))
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", config.key.clone()))
+        .body(work)
+        .send()
+        .await
+        .map_err(|e| ScrapeError::Api(Some(e)))?;
+
+    check_rate_limit(&req)?;
+    if !req.status().is_success() {
+        return Err(ScrapeError::Api(None));
     }
 
     Ok(())
 }
 
+/// Keeps the local work buffer topped up from the server
+async fn prefetch_loop(client: reqwest::Client, config: Config, work_tx: flume::Sender<String>) {
+    let prefetch = work_tx.capacity().unwrap_or(1);
+    loop {
+        let count = prefetch.saturating_sub(work_tx.len()).max(1);
+        match fetch_work(&client, &config, count).await {
+            Ok(work) if !work.is_empty() => {
+                for url in work {
+                    // Blocks while the buffer is full
+                    if work_tx.send_async(url).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Ok(_) => {
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            }
+            Err(ScrapeError::RateLimited(retry_after)) => {
+                println!("Rate limited, waiting {:?} for more work", retry_after);
+                tokio::time::sleep(retry_after).await;
+            }
+            Err(e) => {
+                eprintln!("Error fetching work: {}", e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Collects finished results and reports them back to the server in batches
+async fn submit_loop(
+    client: reqwest::Client,
+    config: Config,
+    result_rx: flume::Receiver<WorkSchema>,
+) {
+    while let Ok(first) = result_rx.recv_async().await {
+        let mut batch = vec![first];
+        batch.extend(result_rx.try_iter().take(MAX_SUBMIT_BATCH - 1));
+
+        let mut attempt = 0;
+        while attempt < SUBMIT_ATTEMPTS {
+            match submit_work(&client, &config, &batch).await {
+                Ok(()) => break,
+                // Being rate limited isn't a failure, so wait it out without using up an attempt
+                Err(ScrapeError::RateLimited(retry_after)) => {
+                    tokio::time::sleep(retry_after).await;
+                }
+                Err(e) => {
+                    attempt += 1;
+                    eprintln!(
+                        "Error submitting {} results (attempt {}): {}",
+                        batch.len(),
+                        attempt,
+                        e
+                    );
+                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                }
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let config_path = std::env::args().nth(1).unwrap_or("config.json".to_string());
@@ -391,15 +615,31 @@ async fn main() -> anyhow::Result<()> {
     let (shutdown_tx, shutdown_rx) = flume::unbounded();
 
     let drivers = config.drivers.clone();
-    let count = if drivers.is_some() {
-        drivers.as_ref().unwrap().len()
+    let count = if let Some(drivers) = &drivers {
+        drivers.len()
     } else {
         config.tasks.unwrap_or(1)
     };
 
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(10))
+        .user_agent(USER_AGENT)
+        .redirect(redirect::Policy::limited(10))
+        .build()?;
+
+    // Tasks pull from a shared buffer of claimed URLs, and push their results
+    // to a shared queue that gets reported back in batches
+    let prefetch = config.prefetch.unwrap_or(count * 2).max(1);
+    let (work_tx, work_rx) = flume::bounded::<String>(prefetch);
+    let (result_tx, result_rx) = flume::unbounded::<WorkSchema>();
+
+    tokio::spawn(prefetch_loop(client.clone(), config.clone(), work_tx));
+    tokio::spawn(submit_loop(client.clone(), config.clone(), result_rx));
+
     for i in 0..count {
-        let driver = if drivers.is_some() {
-            let host = drivers.as_ref().unwrap()[i].clone();
+        let driver = if let Some(drivers) = &drivers {
+            let host = drivers[i].clone();
             let mut caps = DesiredCapabilities::chrome();
             caps.add_chrome_arg(&format!("--user-agent={}", USER_AGENT))?;
             let driver = WebDriver::new(&host, caps).await?;
@@ -408,15 +648,11 @@ async fn main() -> anyhow::Result<()> {
             None
         };
 
-        let client = reqwest::Client::builder()
-            .connect_timeout(Duration::from_secs(5))
-            .timeout(Duration::from_secs(10))
-            .user_agent(USER_AGENT)
-            .redirect(redirect::Policy::limited(10))
-            .build()?;
-
+        let client = client.clone();
         let config = config.clone();
         let shutdown_rx = shutdown_rx.clone();
+        let work_rx = work_rx.clone();
+        let result_tx = result_tx.clone();
         tasks.push(tokio::spawn(async move {
             loop {
                 if shutdown_rx.try_recv().is_ok() {
@@ -427,12 +663,14 @@ async fn main() -> anyhow::Result<()> {
                     break;
                 }
 
-                if let Err(e) = try_work(&client, &driver, &config).await {
+                let Ok(url) = work_rx.recv_async().await else {
+                    break;
+                };
+
+                if let Err(e) = try_work(&client, &driver, &config, &url, &result_tx).await {
                     eprintln!("Error: {}", e);
                     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
                 }
-
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
             }
         }));
     }
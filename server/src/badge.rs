@@ -0,0 +1,61 @@
+use image::{io::Reader, ImageFormat};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+/// The largest badge upload accepted, in bytes
+pub const MAX_BADGE_SIZE: usize = 1024 * 1024;
+
+/// Badges are 88x31, give or take the same few pixels the scraper allows
+const WIDTH: u32 = 88;
+const HEIGHT: u32 = 31;
+const NUDGE: u32 = 2;
+
+/// Formats that browsers will show in an `<img>`, which is all a badge needs to be
+const FORMATS: [ImageFormat; 5] = [
+    ImageFormat::Png,
+    ImageFormat::Gif,
+    ImageFormat::Jpeg,
+    ImageFormat::WebP,
+    ImageFormat::Bmp,
+];
+
+/// The content type to serve a stored badge with, or `None` if it isn't in a format
+/// badges are allowed to be.
+///
+/// Badges saved before uploads were validated could be anything, so this is checked
+/// on the way out too.
+pub fn content_type(data: &[u8]) -> Option<&'static str> {
+    image::guess_format(data)
+        .ok()
+        .filter(|x| FORMATS.contains(x))
+        .map(|x| x.to_mime_type())
+}
+
+/// Checks that an uploaded badge really is the image its hash says it is.
+///
+/// This decodes the whole image, so it should be run off the async runtime.
+pub fn validate(sha256: &str, data: &[u8]) -> anyhow::Result<()> {
+    if data.len() > MAX_BADGE_SIZE {
+        anyhow::bail!("badge is larger than {} bytes", MAX_BADGE_SIZE);
+    }
+
+    let hash = format!("{:x}", Sha256::digest(data));
+    if !hash.eq_ignore_ascii_case(sha256) {
+        anyhow::bail!("badge hash is {}", hash);
+    }
+
+    let reader = Reader::new(Cursor::new(data)).with_guessed_format()?;
+    match reader.format() {
+        Some(format) if FORMATS.contains(&format) => {}
+        format => anyhow::bail!("unsupported badge format {:?}", format),
+    }
+
+    // Check the header before decoding so a huge image can't be smuggled in compressed
+    let (width, height) = reader.into_dimensions()?;
+    if width.abs_diff(WIDTH) > NUDGE || height.abs_diff(HEIGHT) > NUDGE {
+        anyhow::bail!("badge is {}x{}, not 88x31", width, height);
+    }
+
+    image::load_from_memory(data)?;
+    Ok(())
+}
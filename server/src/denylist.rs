@@ -0,0 +1,265 @@
+use crate::{get_domain, leases, storage::Storage, verify, AppResult, AppState};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use base64::Engine;
+use serde::Serialize;
+
+/// How many queued pages to check per round trip to the database when dequeueing
+const DEQUEUE_BATCH: usize = 500;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Denylist {
+    pub domains: Vec<String>,
+    pub rules: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DenylistUpdate {
+    pub entry: String,
+    /// How many queued pages were dropped because of the new entry
+    pub dequeued: usize,
+    /// How many pages out with scrapers or awaiting verification were dropped
+    pub revoked: usize,
+}
+
+/// A denylist entry, either a single domain or a `*.example.com` rule that
+/// covers every subdomain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Entry {
+    Domain(String),
+    Suffix(String),
+}
+
+impl Entry {
+    fn parse(entry: &str) -> Option<Self> {
+        let entry = entry.trim().to_lowercase();
+        let (suffix, domain) = match entry.strip_prefix("*.") {
+            Some(domain) => (true, domain),
+            None => (false, entry.as_str()),
+        };
+
+        let domain = get_domain(&format!("https://{}/", domain)).filter(|x| x == domain)?;
+        Some(if suffix {
+            Entry::Suffix(domain)
+        } else {
+            Entry::Domain(domain)
+        })
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            Entry::Domain(x) => domain == x,
+            Entry::Suffix(x) => domain
+                .strip_suffix(x.as_str())
+                .is_some_and(|x| x.ends_with('.')),
+        }
+    }
+}
+
+/// Checks a domain against both the exact entries in `domains:denylist` and
+/// the suffix rules in `domains:denylist:rules`.
+pub async fn is_denylisted(
+    db: &dyn Storage,
+    state: &AppState,
+    domain: &str,
+) -> anyhow::Result<bool> {
+    let domain_b64 = state.base64.encode(domain.as_bytes());
+    if db.sismember("domains:denylist", &domain_b64).await? {
+        return Ok(true);
+    }
+
+    let rules = db.smembers("domains:denylist:rules").await?;
+    Ok(rules
+        .iter()
+        .any(|x| Entry::Suffix(x.clone()).matches(domain)))
+}
+
+/// Drops every queued page that `entry` covers.
+async fn dequeue(state: &AppState, entry: &Entry) -> anyhow::Result<usize> {
+    let db = &*state.db;
+    let queue = db.zrange("pages:queue", 0, -1).await?;
+
+    let mut count = 0;
+    for chunk in queue.chunks(DEQUEUE_BATCH) {
+        let mut denied = Vec::new();
+        for page in chunk {
+            let url = String::from_utf8(state.base64.decode(page)?)?;
+            if get_domain(&url).is_some_and(|x| entry.matches(&x)) {
+                denied.push(page.clone());
+            }
+        }
+
+        if !denied.is_empty() {
+            count += db.zrem_many("pages:queue", &denied).await?;
+        }
+    }
+
+    Ok(count)
+}
+
+pub async fn list(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let db = &*state.db;
+    let mut domains = Vec::new();
+    for domain in db.smembers("domains:denylist").await? {
+        domains.push(String::from_utf8(state.base64.decode(domain)?)?);
+    }
+    domains.sort();
+
+    let mut rules = db
+        .smembers("domains:denylist:rules")
+        .await?
+        .into_iter()
+        .map(|x| format!("*.{}", x))
+        .collect::<Vec<_>>();
+    rules.sort();
+
+    Ok(Json(Denylist { domains, rules }).into_response())
+}
+
+pub async fn add(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    entry: String,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let Some(entry) = Entry::parse(&entry) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let db = &*state.db;
+    match &entry {
+        Entry::Domain(domain) => {
+            let domain = state.base64.encode(domain.as_bytes());
+            db.sadd("domains:denylist", &domain).await?;
+        }
+        Entry::Suffix(domain) => {
+            db.sadd("domains:denylist:rules", domain).await?;
+        }
+    }
+
+    let dequeued = dequeue(&state, &entry).await?;
+    // Pages already handed out, or waiting on verification, would otherwise be written back
+    let revoked = leases::revoke_where(db, &state, |x| entry.matches(x)).await?
+        + verify::drop_where(db, |x| entry.matches(x)).await?;
+    let entry = match entry {
+        Entry::Domain(domain) => domain,
+        Entry::Suffix(domain) => format!("*.{}", domain),
+    };
+    println!(
+        "Denylisted {}, dropping {} queued and {} in-progress pages",
+        entry, dequeued, revoked
+    );
+
+    Ok(Json(DenylistUpdate {
+        entry,
+        dequeued,
+        revoked,
+    })
+    .into_response())
+}
+
+pub async fn remove(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Path(entry): Path<String>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let Some(entry) = Entry::parse(&entry) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let db = &*state.db;
+    let removed = match entry {
+        Entry::Domain(domain) => {
+            let domain = state.base64.encode(domain.as_bytes());
+            db.srem("domains:denylist", &domain).await?
+        }
+        Entry::Suffix(domain) => db.srem("domains:denylist:rules", &domain).await?,
+    };
+
+    if !removed {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_parse() {
+        assert_eq!(
+            Entry::parse(" *.Example.com "),
+            Some(Entry::Suffix("example.com".to_string()))
+        );
+        assert_eq!(
+            Entry::parse("example.com"),
+            Some(Entry::Domain("example.com".to_string()))
+        );
+        assert_eq!(Entry::parse("*."), None);
+        assert_eq!(Entry::parse("example.com/path"), None);
+    }
+
+    #[test]
+    fn suffixes_only_match_subdomains() {
+        let entry = Entry::Suffix("example.com".to_string());
+        assert!(entry.matches("a.example.com"));
+        assert!(entry.matches("a.b.example.com"));
+        assert!(!entry.matches("example.com"));
+        assert!(!entry.matches("badexample.com"));
+        assert!(!entry.matches("example.com.evil.net"));
+    }
+
+    #[tokio::test]
+    async fn rules_are_checked_alongside_domains() {
+        let state = AppState::test(serde_json::json!({}));
+        let db = &*state.db;
+        db.sadd("domains:denylist:rules", "example.com")
+            .await
+            .unwrap();
+        db.sadd(
+            "domains:denylist",
+            &state.base64.encode("other.net".as_bytes()),
+        )
+        .await
+        .unwrap();
+
+        for (domain, denied) in [
+            ("www.example.com", true),
+            ("example.com", false),
+            ("notexample.com", false),
+            ("other.net", true),
+            ("www.other.net", false),
+        ] {
+            assert_eq!(
+                is_denylisted(db, &state, domain).await.unwrap(),
+                denied,
+                "{}",
+                domain
+            );
+        }
+    }
+}
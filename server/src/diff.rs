@@ -0,0 +1,182 @@
+use crate::{
+    graph::{self, Graph},
+    keys, AppResult, AppState,
+};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+
+#[derive(Serialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub struct Badge {
+    /// The domain the badge links to
+    pub domain: String,
+    pub hash: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphDiff {
+    pub from: i64,
+    pub to: i64,
+    pub domains_added: Vec<String>,
+    pub domains_removed: Vec<String>,
+    pub edges_added: Vec<Edge>,
+    pub edges_removed: Vec<Edge>,
+    /// Badges that weren't anywhere in the graph before
+    pub badges_added: Vec<Badge>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GraphDiffQuery {
+    from: i64,
+    to: i64,
+}
+
+fn edges(graph: &Graph) -> BTreeSet<(&str, &str)> {
+    graph
+        .links_to
+        .iter()
+        .flat_map(|(from, links)| links.iter().map(move |to| (from.as_str(), to.as_str())))
+        .collect()
+}
+
+/// Works out what changed between the graphs at two times.
+fn diff(from_time: i64, from: &Graph, to_time: i64, to: &Graph) -> GraphDiff {
+    let from_domains = from.links_to.keys().collect::<BTreeSet<_>>();
+    let to_domains = to.links_to.keys().collect::<BTreeSet<_>>();
+
+    let from_edges = edges(from);
+    let to_edges = edges(to);
+    let edge = |(from, to): &(&str, &str)| Edge {
+        from: from.to_string(),
+        to: to.to_string(),
+    };
+
+    let known_badges = from.images.values().flatten().collect::<HashSet<_>>();
+    let mut badges_added = BTreeSet::new();
+    for (domain, hashes) in &to.images {
+        for hash in hashes {
+            if !known_badges.contains(hash) {
+                badges_added.insert(Badge {
+                    domain: domain.clone(),
+                    hash: hash.clone(),
+                });
+            }
+        }
+    }
+
+    GraphDiff {
+        from: from_time,
+        to: to_time,
+        domains_added: to_domains
+            .difference(&from_domains)
+            .map(|x| x.to_string())
+            .collect(),
+        domains_removed: from_domains
+            .difference(&to_domains)
+            .map(|x| x.to_string())
+            .collect(),
+        edges_added: to_edges.difference(&from_edges).map(edge).collect(),
+        edges_removed: from_edges.difference(&to_edges).map(edge).collect(),
+        badges_added: badges_added.into_iter().collect(),
+    }
+}
+
+/// Compares the graph at two points in time, given as snapshot ids (from
+/// `/graph/snapshots`) or any other timestamp. Other timestamps use the snapshot
+/// before them, and `from` and `to` in the response say which were compared.
+pub async fn graph_diff(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Query(query): Query<GraphDiffQuery>,
+) -> AppResult<Response<Body>> {
+    if !keys::authorize(&state, &token, keys::Scope::Analytics).await? {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let (from_time, from) = graph::at(&state, query.from).await?;
+    let (to_time, to) = graph::at(&state, query.to).await?;
+    Ok(Json(diff(from_time, &from, to_time, &to)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn graph(links: &[(&str, &str)], images: &[(&str, &str)]) -> Graph {
+        let mut links_to: HashMap<String, Vec<String>> = HashMap::new();
+        for (from, to) in links {
+            links_to
+                .entry(from.to_string())
+                .or_default()
+                .push(to.to_string());
+            links_to.entry(to.to_string()).or_default();
+        }
+
+        let mut graph_images: HashMap<String, Vec<String>> = HashMap::new();
+        for (domain, hash) in images {
+            graph_images
+                .entry(domain.to_string())
+                .or_default()
+                .push(hash.to_string());
+        }
+
+        Graph {
+            links_to,
+            images: graph_images,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn diff_finds_what_changed() {
+        let from = graph(&[("a.com", "b.com"), ("b.com", "c.com")], &[("b.com", "1")]);
+        let to = graph(
+            &[("a.com", "b.com"), ("a.com", "d.com")],
+            &[("b.com", "1"), ("c.com", "1"), ("d.com", "2")],
+        );
+
+        let diff = diff(1, &from, 2, &to);
+        assert_eq!((diff.from, diff.to), (1, 2));
+        assert_eq!(diff.domains_added, vec!["d.com"]);
+        assert_eq!(diff.domains_removed, vec!["c.com"]);
+        assert_eq!(
+            diff.edges_added,
+            vec![Edge {
+                from: "a.com".to_string(),
+                to: "d.com".to_string(),
+            }]
+        );
+        assert_eq!(
+            diff.edges_removed,
+            vec![Edge {
+                from: "b.com".to_string(),
+                to: "c.com".to_string(),
+            }]
+        );
+        // The same badge turning up somewhere else isn't new
+        assert_eq!(
+            diff.badges_added,
+            vec![Badge {
+                domain: "d.com".to_string(),
+                hash: "2".to_string(),
+            }]
+        );
+    }
+}
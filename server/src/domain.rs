@@ -0,0 +1,310 @@
+use crate::{
+    failures, get_domain, graph, history, keys, politeness, resolve_page,
+    storage::{Op, Storage},
+    AppResult, AppState,
+};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use base64::Engine;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DomainDetail {
+    pub domain: String,
+    /// Estimated number of pages seen on the domain, from its HyperLogLog
+    pub page_estimate: usize,
+    pub pages: Vec<DomainPage>,
+    pub outbound: Vec<DomainLink>,
+    pub inbound: Vec<DomainLink>,
+    pub redirects: Vec<Redirect>,
+    /// Failed scrapes of the domain's pages, by reason
+    pub failures: BTreeMap<String, u64>,
+    /// Pages the domain's robots.txt keeps us out of
+    pub disallowed: Vec<Disallowed>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DomainPage {
+    pub url: String,
+    pub last_scraped: Option<i64>,
+    pub visited: bool,
+    pub failed: bool,
+    /// Failed too many times in a row to be scraped again
+    pub dead: bool,
+    #[serde(flatten)]
+    pub failures: failures::PageFailures,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DomainLink {
+    pub domain: String,
+    pub badges: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Disallowed {
+    pub url: String,
+    /// When a scraper was turned away
+    pub reported: i64,
+    /// When the page is next checked, in case robots.txt has changed
+    pub expires: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Redirect {
+    pub from: String,
+    pub to: String,
+}
+
+/// Records a redirect against both domains involved, so they can be found without scanning.
+pub async fn index_redirect(
+    db: &dyn Storage,
+    state: &AppState,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<()> {
+    db.apply(redirect_ops(state, from, to)).await
+}
+
+/// The writes behind [`index_redirect`], for applying alongside others.
+pub fn redirect_ops(state: &AppState, from: &str, to: &str) -> Vec<Op> {
+    let from_b64 = state.base64.encode(from.as_bytes());
+    [get_domain(from), get_domain(to)]
+        .into_iter()
+        .flatten()
+        .map(|domain| {
+            let domain = state.base64.encode(domain.as_bytes());
+            Op::SAdd(format!("domain:redirects:{}", domain), from_b64.clone())
+        })
+        .collect()
+}
+
+/// Indexes the redirects recorded before `domain:redirects:*` existed.
+pub async fn migrate(state: &AppState) -> anyhow::Result<()> {
+    let db = &*state.db;
+    if db.sismember("migrations", "domain-redirects").await? {
+        return Ok(());
+    }
+
+    println!("Indexing redirects by domain...");
+    let mut count = 0;
+    for key in db.scan("redirect:*").await? {
+        let Some(from) = key.strip_prefix("redirect:") else {
+            continue;
+        };
+        let Some(to) = db.get(&key).await? else {
+            continue;
+        };
+
+        let from = String::from_utf8(state.base64.decode(from)?)?;
+        let to = String::from_utf8(state.base64.decode(to)?)?;
+        index_redirect(db, state, &from, &to).await?;
+        count += 1;
+    }
+
+    db.sadd("migrations", "domain-redirects").await?;
+    println!("Indexed {} redirects", count);
+    Ok(())
+}
+
+/// One of the domain's pages, along with its links to and from other domains.
+struct PageDetail {
+    page: DomainPage,
+    /// The domain on the other end of each link, and the badge used for it
+    outbound: Vec<(String, Option<String>)>,
+    inbound: Vec<(String, Option<String>)>,
+}
+
+/// The domain on the other end of a live link and the link's badge, unless it stays on `domain`.
+async fn other_end(
+    db: &dyn Storage,
+    state: &AppState,
+    domain: &str,
+    other: &str,
+    link_key: String,
+) -> anyhow::Result<Option<(String, Option<String>)>> {
+    let url = resolve_page(db, state, other).await?;
+    let Some(link_domain) = get_domain(&url).filter(|x| x != domain) else {
+        return Ok(None);
+    };
+
+    let data = db.hgetall(&link_key).await?;
+    if !history::Seen::from_fields(&data).visible(None) {
+        return Ok(None);
+    }
+    Ok(Some((link_domain, data.get("imageHash").cloned())))
+}
+
+async fn page_detail(
+    db: &dyn Storage,
+    state: &AppState,
+    domain: &str,
+    page_b64: &str,
+    page: &str,
+) -> anyhow::Result<PageDetail> {
+    let data = db.hgetall(&format!("pages:data:{}", page_b64)).await?;
+    let last_scraped = data
+        .get("lastScraped")
+        .and_then(|x| x.parse::<i64>().ok())
+        .filter(|x| *x != 0);
+    let page = DomainPage {
+        url: page.to_string(),
+        last_scraped,
+        visited: db.sismember("pages:visited", page_b64).await?,
+        failed: db.sismember("pages:failed", page_b64).await?,
+        dead: db.sismember("pages:dead", page_b64).await?,
+        failures: failures::PageFailures::from_fields(&data),
+    };
+
+    let links_to = db
+        .smembers(&format!("pages:linksto:{}", page_b64))
+        .await
+        .unwrap_or_default();
+    let outbound = futures::future::try_join_all(links_to.iter().map(|link_to| {
+        let link_key = format!("link:{}:{}", page_b64, link_to);
+        other_end(db, state, domain, link_to, link_key)
+    }))
+    .await?
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let linked_from = db
+        .smembers(&format!("pages:linkedfrom:{}", page_b64))
+        .await
+        .unwrap_or_default();
+    let inbound = futures::future::try_join_all(linked_from.iter().map(|link_from| {
+        let link_key = format!("link:{}:{}", link_from, page_b64);
+        other_end(db, state, domain, link_from, link_key)
+    }))
+    .await?
+    .into_iter()
+    .flatten()
+    .collect();
+
+    Ok(PageDetail {
+        page,
+        outbound,
+        inbound,
+    })
+}
+
+pub async fn detail(state: &AppState, domain: &str) -> anyhow::Result<DomainDetail> {
+    let domain_b64 = state.base64.encode(domain.as_bytes());
+
+    let db = &*state.db;
+
+    let mut pages = Vec::new();
+    for page_b64 in db.smembers("pages").await? {
+        let page = String::from_utf8(state.base64.decode(&page_b64)?)?;
+        if get_domain(&page).as_deref() == Some(domain) {
+            pages.push((page_b64, page));
+        }
+    }
+
+    let mut detail = DomainDetail {
+        domain: domain.to_string(),
+        page_estimate: 0,
+        pages: Vec::new(),
+        outbound: Vec::new(),
+        inbound: Vec::new(),
+        redirects: Vec::new(),
+        failures: failures::domain_failures(db, state, domain).await?,
+        disallowed: Vec::new(),
+    };
+    let mut outbound: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let mut inbound: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    for chunk in pages.chunks(graph::BUILD_BATCH) {
+        let fetched = futures::future::try_join_all(
+            chunk
+                .iter()
+                .map(|(page_b64, page)| page_detail(db, state, domain, page_b64, page)),
+        )
+        .await?;
+
+        for page in fetched {
+            detail.pages.push(page.page);
+            for (link_domain, badge) in page.outbound {
+                outbound.entry(link_domain).or_default().extend(badge);
+            }
+            for (link_domain, badge) in page.inbound {
+                inbound.entry(link_domain).or_default().extend(badge);
+            }
+        }
+    }
+
+    detail.page_estimate = db.pfcount(&format!("domain:pages:{}", domain_b64)).await?;
+
+    let redirects = db
+        .smembers(&format!("domain:redirects:{}", domain_b64))
+        .await?;
+    for from_b64 in redirects {
+        let to = db.get(&format!("redirect:{}", from_b64)).await?;
+        let Some(to) = to else {
+            continue;
+        };
+
+        detail.redirects.push(Redirect {
+            from: String::from_utf8(state.base64.decode(from_b64)?)?,
+            to: String::from_utf8(state.base64.decode(to)?)?,
+        });
+    }
+
+    for disallow in politeness::disallowed(db, state, domain).await? {
+        detail.disallowed.push(Disallowed {
+            url: String::from_utf8(state.base64.decode(&disallow.page)?)?,
+            reported: disallow.reported,
+            expires: disallow.expires,
+        });
+    }
+
+    detail.pages.sort_by(|a, b| a.url.cmp(&b.url));
+    detail.disallowed.sort_by(|a, b| a.url.cmp(&b.url));
+    detail.redirects.sort_by(|a, b| a.from.cmp(&b.from));
+    detail.outbound = outbound
+        .into_iter()
+        .map(|(domain, badges)| DomainLink {
+            domain,
+            badges: badges.into_iter().collect(),
+        })
+        .collect();
+    detail.inbound = inbound
+        .into_iter()
+        .map(|(domain, badges)| DomainLink {
+            domain,
+            badges: badges.into_iter().collect(),
+        })
+        .collect();
+
+    Ok(detail)
+}
+
+pub async fn domain(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Path(domain): Path<String>,
+) -> AppResult<Response<Body>> {
+    if !keys::authorize(&state, &token, keys::Scope::Analytics).await? {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let detail = detail(&state, &domain.to_lowercase()).await?;
+    if detail.pages.is_empty() && detail.inbound.is_empty() && detail.redirects.is_empty() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    Ok(Json(detail).into_response())
+}
@@ -0,0 +1,277 @@
+use crate::graph::Graph;
+use serde::Deserialize;
+use std::fmt::Write;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    GraphMl,
+    Gexf,
+    Dot,
+    Csv,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::GraphMl => "application/graphml+xml",
+            Format::Gexf => "application/gexf+xml",
+            Format::Dot => "text/vnd.graphviz",
+            Format::Csv => "text/csv",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::GraphMl => "graphml",
+            Format::Gexf => "gexf",
+            Format::Dot => "dot",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+/// A flattened view of the graph that all of the export formats share.
+struct Export<'a> {
+    nodes: Vec<Node<'a>>,
+    edges: Vec<Edge<'a>>,
+}
+
+struct Node<'a> {
+    domain: &'a str,
+    inbound: usize,
+    outbound: usize,
+}
+
+struct Edge<'a> {
+    from: &'a str,
+    to: &'a str,
+    badges: Vec<&'a str>,
+}
+
+impl<'a> Export<'a> {
+    fn new(graph: &'a Graph) -> Self {
+        let mut nodes = graph
+            .links_to
+            .iter()
+            .map(|(domain, links_to)| Node {
+                domain,
+                inbound: graph.linked_from.get(domain).map_or(0, |x| x.len()),
+                outbound: links_to.len(),
+            })
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|node| node.domain);
+
+        let mut edges = Vec::new();
+        for node in &nodes {
+            for to in &graph.links_to[node.domain] {
+                let badges = graph
+                    .link_images
+                    .get(node.domain)
+                    .and_then(|links| links.get(to))
+                    .map(|hashes| hashes.iter().map(|x| x.as_str()).collect())
+                    .unwrap_or_default();
+                edges.push(Edge {
+                    from: node.domain,
+                    to,
+                    badges,
+                });
+            }
+        }
+
+        Self { nodes, edges }
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_csv(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn graphml(graph: &Graph) -> anyhow::Result<String> {
+    let export = Export::new(graph);
+    let mut out = String::new();
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+    )?;
+    writeln!(
+        out,
+        r#"  <key id="inbound" for="node" attr.name="inbound" attr.type="int"/>"#
+    )?;
+    writeln!(
+        out,
+        r#"  <key id="outbound" for="node" attr.name="outbound" attr.type="int"/>"#
+    )?;
+    writeln!(
+        out,
+        r#"  <key id="badges" for="edge" attr.name="badges" attr.type="string"/>"#
+    )?;
+    writeln!(out, r#"  <graph id="G" edgedefault="directed">"#)?;
+
+    for node in &export.nodes {
+        writeln!(out, r#"    <node id="{}">"#, escape_xml(node.domain))?;
+        writeln!(out, r#"      <data key="inbound">{}</data>"#, node.inbound)?;
+        writeln!(
+            out,
+            r#"      <data key="outbound">{}</data>"#,
+            node.outbound
+        )?;
+        writeln!(out, "    </node>")?;
+    }
+
+    for (i, edge) in export.edges.iter().enumerate() {
+        writeln!(
+            out,
+            r#"    <edge id="e{}" source="{}" target="{}">"#,
+            i,
+            escape_xml(edge.from),
+            escape_xml(edge.to)
+        )?;
+        writeln!(
+            out,
+            r#"      <data key="badges">{}</data>"#,
+            escape_xml(&edge.badges.join(" "))
+        )?;
+        writeln!(out, "    </edge>")?;
+    }
+
+    writeln!(out, "  </graph>")?;
+    writeln!(out, "</graphml>")?;
+    Ok(out)
+}
+
+pub fn gexf(graph: &Graph) -> anyhow::Result<String> {
+    let export = Export::new(graph);
+    let mut out = String::new();
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<gexf xmlns="http://gexf.net/1.3" version="1.3">"#)?;
+    writeln!(out, r#"  <graph defaultedgetype="directed">"#)?;
+    writeln!(out, r#"    <attributes class="node">"#)?;
+    writeln!(
+        out,
+        r#"      <attribute id="inbound" title="inbound" type="integer"/>"#
+    )?;
+    writeln!(
+        out,
+        r#"      <attribute id="outbound" title="outbound" type="integer"/>"#
+    )?;
+    writeln!(out, "    </attributes>")?;
+    writeln!(out, r#"    <attributes class="edge">"#)?;
+    writeln!(
+        out,
+        r#"      <attribute id="badges" title="badges" type="string"/>"#
+    )?;
+    writeln!(out, "    </attributes>")?;
+
+    writeln!(out, "    <nodes>")?;
+    for node in &export.nodes {
+        let domain = escape_xml(node.domain);
+        writeln!(out, r#"      <node id="{}" label="{}">"#, domain, domain)?;
+        writeln!(out, "        <attvalues>")?;
+        writeln!(
+            out,
+            r#"          <attvalue for="inbound" value="{}"/>"#,
+            node.inbound
+        )?;
+        writeln!(
+            out,
+            r#"          <attvalue for="outbound" value="{}"/>"#,
+            node.outbound
+        )?;
+        writeln!(out, "        </attvalues>")?;
+        writeln!(out, "      </node>")?;
+    }
+    writeln!(out, "    </nodes>")?;
+
+    writeln!(out, "    <edges>")?;
+    for (i, edge) in export.edges.iter().enumerate() {
+        writeln!(
+            out,
+            r#"      <edge id="{}" source="{}" target="{}">"#,
+            i,
+            escape_xml(edge.from),
+            escape_xml(edge.to)
+        )?;
+        writeln!(out, "        <attvalues>")?;
+        writeln!(
+            out,
+            r#"          <attvalue for="badges" value="{}"/>"#,
+            escape_xml(&edge.badges.join(" "))
+        )?;
+        writeln!(out, "        </attvalues>")?;
+        writeln!(out, "      </edge>")?;
+    }
+    writeln!(out, "    </edges>")?;
+
+    writeln!(out, "  </graph>")?;
+    writeln!(out, "</gexf>")?;
+    Ok(out)
+}
+
+pub fn dot(graph: &Graph) -> anyhow::Result<String> {
+    let export = Export::new(graph);
+    let mut out = String::new();
+
+    writeln!(out, "digraph eightyeightthirtyone {{")?;
+    for node in &export.nodes {
+        writeln!(
+            out,
+            r#"  "{}" [inbound={}, outbound={}];"#,
+            escape_dot(node.domain),
+            node.inbound,
+            node.outbound
+        )?;
+    }
+    for edge in &export.edges {
+        writeln!(
+            out,
+            r#"  "{}" -> "{}" [badges="{}"];"#,
+            escape_dot(edge.from),
+            escape_dot(edge.to),
+            escape_dot(&edge.badges.join(" "))
+        )?;
+    }
+    writeln!(out, "}}")?;
+    Ok(out)
+}
+
+/// An edge list, with the badge hashes on each edge separated by spaces.
+pub fn csv(graph: &Graph) -> anyhow::Result<String> {
+    let export = Export::new(graph);
+    let mut out = String::new();
+
+    writeln!(out, "source,target,badges")?;
+    for edge in &export.edges {
+        writeln!(
+            out,
+            "{},{},{}",
+            escape_csv(edge.from),
+            escape_csv(edge.to),
+            escape_csv(&edge.badges.join(" "))
+        )?;
+    }
+    Ok(out)
+}
@@ -0,0 +1,251 @@
+use crate::{
+    recrawl,
+    storage::{Op, Storage},
+    AppState,
+};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// How long to wait before retrying a page after its first failure, in seconds.
+///
+/// This doubles with every failure in a row, up to the recrawl interval.
+pub const DEFAULT_FAILURE_BACKOFF: u64 = 60 * 60;
+
+/// How many failures in a row before a page is given up on
+pub const DEFAULT_MAX_FAILURES: u64 = 5;
+
+/// Why a scraper couldn't scrape a page.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    Dns,
+    Timeout,
+    /// The page responded with an error status, sent along as `http_status`
+    HttpStatus,
+    NotHtml,
+    Parse,
+    /// The browser fell over while loading the page
+    Webdriver,
+}
+
+impl FailureReason {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::Timeout => "timeout",
+            Self::HttpStatus => "http_status",
+            Self::NotHtml => "not_html",
+            Self::Parse => "parse",
+            Self::Webdriver => "webdriver",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "dns" => Self::Dns,
+            "timeout" => Self::Timeout,
+            "http_status" => Self::HttpStatus,
+            "not_html" => Self::NotHtml,
+            "parse" => Self::Parse,
+            "webdriver" => Self::Webdriver,
+            _ => return None,
+        })
+    }
+}
+
+/// Failures from older scrapers don't say why, so they're counted under this
+const UNKNOWN_REASON: &str = "unknown";
+
+/// The failure state of a page, as stored in its `pages:data:*` hash.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PageFailures {
+    /// Failures since the page was last scraped successfully
+    pub failures: u64,
+    pub last_failure: Option<i64>,
+    pub failure_reason: Option<FailureReason>,
+    pub http_status: Option<u16>,
+}
+
+impl PageFailures {
+    pub fn from_fields(fields: &HashMap<String, String>) -> Self {
+        Self {
+            failures: fields
+                .get("failures")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(0),
+            last_failure: fields.get("lastFailure").and_then(|x| x.parse().ok()),
+            failure_reason: fields
+                .get("failureReason")
+                .and_then(|x| FailureReason::from_name(x)),
+            http_status: fields.get("httpStatus").and_then(|x| x.parse().ok()),
+        }
+    }
+}
+
+pub fn max_failures(state: &AppState) -> u64 {
+    state
+        .config
+        .max_failures
+        .unwrap_or(DEFAULT_MAX_FAILURES)
+        .max(1)
+}
+
+/// How long to wait before retrying a page that's failed `failures` times in a row.
+pub fn backoff(state: &AppState, failures: u64) -> i64 {
+    let base = state
+        .config
+        .failure_backoff
+        .unwrap_or(DEFAULT_FAILURE_BACKOFF) as i64;
+    let doublings = failures.saturating_sub(1).min(32) as u32;
+    base.saturating_mul(1 << doublings)
+        .min(recrawl::recrawl_interval(state))
+}
+
+pub async fn failures(db: &dyn Storage, page: &str) -> anyhow::Result<u64> {
+    Ok(db
+        .hget(&format!("pages:data:{}", page), "failures")
+        .await?
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(0))
+}
+
+/// The writes for a failed scrape of `page`, which has already failed `previous` times in a row.
+///
+/// The page is retried after a backoff, or marked dead once it's failed too often.
+pub fn failure_ops(
+    state: &AppState,
+    page: &str,
+    domain: &str,
+    previous: u64,
+    reason: Option<FailureReason>,
+    http_status: Option<u16>,
+    now: i64,
+) -> Vec<Op> {
+    let failures = previous + 1;
+    let data_key = format!("pages:data:{}", page);
+    let reason_name = reason.map(|x| x.name()).unwrap_or(UNKNOWN_REASON);
+
+    let mut fields = HashMap::from_iter(vec![
+        ("failures".to_string(), failures.to_string()),
+        ("lastFailure".to_string(), now.to_string()),
+        ("failureReason".to_string(), reason_name.to_string()),
+    ]);
+    let mut ops = Vec::new();
+    match http_status {
+        Some(status) => {
+            fields.insert("httpStatus".to_string(), status.to_string());
+        }
+        None => ops.push(Op::HDel(data_key.clone(), "httpStatus".to_string())),
+    }
+    ops.push(Op::HSet(data_key, fields));
+
+    let domain = state.base64.encode(domain.as_bytes());
+    ops.push(Op::HIncrBy(
+        format!("domain:failures:{}", domain),
+        reason_name.to_string(),
+        1,
+    ));
+    ops.push(Op::SAdd("pages:failed".to_string(), page.to_string()));
+
+    if failures >= max_failures(state) {
+        ops.push(Op::SAdd("pages:dead".to_string(), page.to_string()));
+        ops.push(recrawl::unschedule_op(page));
+    } else {
+        ops.push(recrawl::schedule_op(page, now + backoff(state, failures)));
+    }
+
+    ops
+}
+
+/// The writes for a successful scrape of `page`, which clear any failures it had.
+pub fn success_ops(page: &str) -> Vec<Op> {
+    let data_key = format!("pages:data:{}", page);
+    let mut ops: Vec<Op> = ["failures", "lastFailure", "failureReason", "httpStatus"]
+        .into_iter()
+        .map(|field| Op::HDel(data_key.clone(), field.to_string()))
+        .collect();
+    ops.push(Op::SRem("pages:failed".to_string(), page.to_string()));
+    ops.push(Op::SRem("pages:dead".to_string(), page.to_string()));
+    ops
+}
+
+/// How often scrapes on `domain` have failed, by reason.
+pub async fn domain_failures(
+    db: &dyn Storage,
+    state: &AppState,
+    domain: &str,
+) -> anyhow::Result<BTreeMap<String, u64>> {
+    let domain = state.base64.encode(domain.as_bytes());
+    let counts = db.hgetall(&format!("domain:failures:{}", domain)).await?;
+    Ok(counts
+        .into_iter()
+        .filter_map(|(reason, count)| Some((reason, count.parse().ok()?)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_recrawl_interval() {
+        let state = AppState::test(serde_json::json!({
+            "failure_backoff": 60,
+            "recrawl_interval": 1000,
+        }));
+        assert_eq!(backoff(&state, 1), 60);
+        assert_eq!(backoff(&state, 2), 120);
+        assert_eq!(backoff(&state, 4), 480);
+        assert_eq!(backoff(&state, 5), 960);
+        assert_eq!(backoff(&state, 6), 1000);
+        assert_eq!(backoff(&state, u64::MAX), 1000);
+    }
+
+    #[tokio::test]
+    async fn pages_die_after_too_many_failures() {
+        let state = AppState::test(serde_json::json!({
+            "max_failures": 3,
+            "failure_backoff": 60,
+        }));
+        let db = &*state.db;
+        let page = "page";
+
+        for i in 1..=3 {
+            let previous = failures(db, page).await.unwrap();
+            assert_eq!(previous, i - 1);
+            let ops = failure_ops(
+                &state,
+                page,
+                "a.com",
+                previous,
+                Some(FailureReason::Timeout),
+                None,
+                1000,
+            );
+            db.apply(ops).await.unwrap();
+
+            let dead = db.sismember("pages:dead", page).await.unwrap();
+            let due = db.zscore("pages:recrawl", page).await.unwrap();
+            if i < 3 {
+                assert!(!dead);
+                assert_eq!(due, Some((1000 + backoff(&state, i)) as f64));
+            } else {
+                assert!(dead);
+                assert_eq!(due, None);
+            }
+        }
+
+        assert_eq!(
+            domain_failures(db, &state, "a.com").await.unwrap(),
+            BTreeMap::from_iter(vec![("timeout".to_string(), 3)])
+        );
+
+        // A success brings it back
+        db.apply(success_ops(page)).await.unwrap();
+        assert_eq!(failures(db, page).await.unwrap(), 0);
+        assert!(!db.sismember("pages:dead", page).await.unwrap());
+        assert!(!db.sismember("pages:failed", page).await.unwrap());
+    }
+}
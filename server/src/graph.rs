@@ -0,0 +1,610 @@
+use crate::{
+    export::{self, Format},
+    get_domain, history, keys, resolve_page,
+    storage::Storage,
+    AppResult, AppState,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{header, response, HeaderMap, Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{Notify, RwLock};
+
+/// How often the graph is rebuilt, in seconds
+pub const DEFAULT_GRAPH_INTERVAL: u64 = 60 * 15;
+
+/// How many results can be posted before the graph is rebuilt early
+pub const DEFAULT_GRAPH_REBUILD_AFTER: u64 = 5000;
+
+/// How many snapshots are kept on disk, for comparing the graph over time
+pub const DEFAULT_GRAPH_SNAPSHOTS: usize = 96;
+
+/// Where snapshots are kept on disk
+const SNAPSHOT_DIR: &str = "./snapshots";
+
+/// How many graphs rebuilt from link history are kept, for times before any snapshot
+const PAST_GRAPHS: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Graph {
+    pub links_to: HashMap<String, Vec<String>>,
+    pub linked_from: HashMap<String, Vec<String>>,
+    pub images: HashMap<String, Vec<String>>,
+    /// Badge hashes on each domain-to-domain link
+    #[serde(default)]
+    pub link_images: HashMap<String, HashMap<String, Vec<String>>>,
+}
+
+impl Graph {
+    /// Takes a domain and every link to or from it out of the graph, returning whether it was there.
+    fn remove_domain(&mut self, domain: &str) -> bool {
+        let mut removed = self.links_to.remove(domain).is_some();
+        removed |= self.linked_from.remove(domain).is_some();
+        removed |= self.images.remove(domain).is_some();
+        removed |= self.link_images.remove(domain).is_some();
+
+        for links in self
+            .links_to
+            .values_mut()
+            .chain(self.linked_from.values_mut())
+        {
+            let before = links.len();
+            links.retain(|x| x != domain);
+            removed |= links.len() != before;
+        }
+        for links in self.link_images.values_mut() {
+            removed |= links.remove(domain).is_some();
+        }
+
+        removed
+    }
+}
+
+/// A built graph, along with its serialized form so requests don't have to redo it
+pub struct Snapshot {
+    pub generated: i64,
+    pub etag: String,
+    pub graph: Graph,
+    pub json: Bytes,
+}
+
+impl Snapshot {
+    fn new(generated: i64, graph: Graph) -> anyhow::Result<Self> {
+        let json = Bytes::from(serde_json::to_vec(&graph)?);
+        Ok(Self::from_parts(generated, graph, json))
+    }
+
+    fn from_parts(generated: i64, graph: Graph, json: Bytes) -> Self {
+        let etag = format!("\"{:x}\"", Sha256::digest(&json));
+        Self {
+            generated,
+            etag,
+            graph,
+            json,
+        }
+    }
+
+    fn last_modified(&self) -> String {
+        chrono::DateTime::from_timestamp(self.generated, 0)
+            .unwrap_or_default()
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    }
+}
+
+#[derive(Default)]
+pub struct GraphCache {
+    snapshot: RwLock<Option<Arc<Snapshot>>>,
+    pending_work: AtomicU64,
+    rebuild: Notify,
+    /// Graphs rebuilt by [`at`], newest last
+    past: Mutex<VecDeque<(i64, Arc<Graph>)>>,
+    /// Held while [`at`] rebuilds a graph, so only one is built at a time
+    building: tokio::sync::Mutex<()>,
+}
+
+impl GraphCache {
+    pub async fn latest(&self) -> Option<Arc<Snapshot>> {
+        self.snapshot.read().await.clone()
+    }
+
+    /// Counts a posted result, kicking off a rebuild once enough have come in.
+    pub fn record_work(&self, state: &AppState) {
+        let threshold = state
+            .config
+            .graph_rebuild_after
+            .unwrap_or(DEFAULT_GRAPH_REBUILD_AFTER);
+        let pending = self.pending_work.fetch_add(1, Ordering::Relaxed) + 1;
+        if threshold > 0 && pending >= threshold {
+            self.rebuild.notify_one();
+        }
+    }
+
+    /// Rebuilds the snapshot straight away, for when data has been removed rather than added.
+    pub fn invalidate(&self) {
+        self.past.lock().unwrap().clear();
+        self.rebuild.notify_one();
+    }
+
+    fn past(&self, at: i64) -> Option<Arc<Graph>> {
+        let past = self.past.lock().unwrap();
+        past.iter().find(|(x, _)| *x == at).map(|(_, x)| x.clone())
+    }
+}
+
+/// How many pages are read from the database at once while building a graph
+pub const BUILD_BATCH: usize = 100;
+
+/// What the graph needs to know about a single page.
+pub struct PageLinks {
+    pub domain: String,
+    /// Where the page links to (with redirects followed), and the badge used for each link
+    pub links_to: Vec<(String, Option<String>)>,
+    /// Where the page is linked from, with redirects followed
+    pub linked_from: Vec<String>,
+}
+
+/// Reads a page's links, or nothing if the page is a redirect or has no domain.
+///
+/// Only links that were on the page at `as_of` (or are on it now) are included.
+pub async fn fetch_page(
+    db: &dyn Storage,
+    state: &AppState,
+    page_b64: &str,
+    as_of: Option<i64>,
+) -> anyhow::Result<Option<PageLinks>> {
+    let redirect = db
+        .get(&format!("redirect:{}", page_b64))
+        .await
+        .unwrap_or(None);
+    if redirect.is_some() {
+        return Ok(None);
+    }
+
+    let page = String::from_utf8(state.base64.decode(page_b64)?)?;
+    let Some(domain) = get_domain(&page) else {
+        return Ok(None);
+    };
+
+    let links_to = db
+        .smembers(&format!("pages:linksto:{}", page_b64))
+        .await
+        .unwrap_or_default();
+    let links_to = futures::future::try_join_all(links_to.iter().map(|link_to| async move {
+        let data = db
+            .hgetall(&format!("link:{}:{}", page_b64, link_to))
+            .await
+            .unwrap_or_default();
+        if !history::Seen::from_fields(&data).visible(as_of) {
+            return anyhow::Ok(None);
+        }
+
+        let url = resolve_page(db, state, link_to).await?;
+        Ok(Some((url, data.get("imageHash").cloned())))
+    }))
+    .await?
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let linked_from = db
+        .smembers(&format!("pages:linkedfrom:{}", page_b64))
+        .await
+        .unwrap_or_default();
+    let linked_from =
+        futures::future::try_join_all(linked_from.iter().map(|link_from| async move {
+            if !history::seen(db, link_from, page_b64).await?.visible(as_of) {
+                return anyhow::Ok(None);
+            }
+
+            Ok(Some(resolve_page(db, state, link_from).await?))
+        }))
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    Ok(Some(PageLinks {
+        domain,
+        links_to,
+        linked_from,
+    }))
+}
+
+/// Walks the database and collapses every page into a domain-level graph.
+///
+/// Pages are read a batch at a time, and nothing is locked while this runs so
+/// scrapers can keep working. With `as_of`, the graph is of the links as they
+/// were at that time instead of as they are now.
+pub async fn build(state: &AppState, as_of: Option<i64>) -> anyhow::Result<Graph> {
+    let db = &*state.db;
+    let mut graph = Graph::default();
+
+    let pages = db.smembers("pages").await?;
+
+    for chunk in pages.chunks(BUILD_BATCH) {
+        let fetched = futures::future::try_join_all(
+            chunk.iter().map(|page| fetch_page(db, state, page, as_of)),
+        )
+        .await?;
+
+        for page in fetched.into_iter().flatten() {
+            let page_domain = page.domain;
+
+            for (url, image_hash) in page.links_to {
+                let Some(link_domain) = get_domain(&url) else {
+                    continue;
+                };
+
+                graph
+                    .links_to
+                    .entry(page_domain.clone())
+                    .or_default()
+                    .push(link_domain.clone());
+
+                graph.links_to.entry(link_domain.clone()).or_default();
+                graph.linked_from.entry(link_domain.clone()).or_default();
+                graph.images.entry(link_domain.clone()).or_default();
+
+                if let Some(image_hash) = image_hash {
+                    let hashes = graph.images.entry(link_domain.clone()).or_default();
+                    if !hashes.contains(&image_hash) {
+                        hashes.push(image_hash.clone());
+                    }
+
+                    graph
+                        .link_images
+                        .entry(page_domain.clone())
+                        .or_default()
+                        .entry(link_domain.clone())
+                        .or_default()
+                        .push(image_hash);
+                }
+            }
+
+            for url in page.linked_from {
+                let Some(link_domain) = get_domain(&url) else {
+                    continue;
+                };
+
+                graph
+                    .linked_from
+                    .entry(page_domain.clone())
+                    .or_default()
+                    .push(link_domain.clone());
+
+                graph.links_to.entry(link_domain.clone()).or_default();
+                graph.linked_from.entry(link_domain.clone()).or_default();
+                graph.images.entry(link_domain.clone()).or_default();
+            }
+        }
+    }
+
+    // Deduplicate
+    for (_, links) in graph.links_to.iter_mut() {
+        links.sort();
+        links.dedup();
+    }
+    for (_, links) in graph.linked_from.iter_mut() {
+        links.sort();
+        links.dedup();
+    }
+    for (_, hashes) in graph.images.iter_mut() {
+        hashes.sort();
+        hashes.dedup();
+    }
+    for (_, links) in graph.link_images.iter_mut() {
+        for (_, hashes) in links.iter_mut() {
+            hashes.sort();
+            hashes.dedup();
+        }
+    }
+
+    Ok(graph)
+}
+
+fn snapshot_path(generated: i64) -> String {
+    format!("{}/graph-{}.json", SNAPSHOT_DIR, generated)
+}
+
+async fn write_snapshot(generated: i64, json: &[u8]) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(SNAPSHOT_DIR).await?;
+
+    // Write to a temporary file first so a crash can't leave a half-written snapshot
+    let path = snapshot_path(generated);
+    let tmp_path = format!("{}.tmp", path);
+    tokio::fs::write(&tmp_path, json).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+/// Writes a snapshot to disk, dropping the oldest ones past the configured number to keep.
+async fn save(state: &AppState, snapshot: &Snapshot) -> anyhow::Result<()> {
+    write_snapshot(snapshot.generated, &snapshot.json).await?;
+
+    let keep = state
+        .config
+        .graph_snapshots
+        .unwrap_or(DEFAULT_GRAPH_SNAPSHOTS)
+        .max(1);
+    let saved = list_saved().await?;
+    for generated in &saved[..saved.len().saturating_sub(keep)] {
+        tokio::fs::remove_file(snapshot_path(*generated)).await.ok();
+    }
+
+    Ok(())
+}
+
+/// Reads the snapshot generated at `generated` back from disk, if it's still there.
+pub async fn load_saved(generated: i64) -> anyhow::Result<Option<Graph>> {
+    match tokio::fs::read(snapshot_path(generated)).await {
+        Ok(json) => Ok(Some(serde_json::from_slice(&json)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Strips a domain out of every snapshot on disk, so it doesn't live on in `/graph/diff`
+/// after being purged.
+pub async fn forget(domain: &str) -> anyhow::Result<()> {
+    for generated in list_saved().await? {
+        let Some(mut graph) = load_saved(generated).await? else {
+            continue;
+        };
+
+        if graph.remove_domain(domain) {
+            write_snapshot(generated, &serde_json::to_vec(&graph)?).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The graph as it was at `at`, along with when it's actually from.
+///
+/// That's the newest snapshot taken at or before `at`. Only times before the
+/// oldest snapshot are rebuilt from link history - those are rounded down to the
+/// graph interval, and built one at a time with the last few kept.
+pub async fn at(state: &AppState, at: i64) -> anyhow::Result<(i64, Arc<Graph>)> {
+    let saved = list_saved().await?;
+    if let Some(generated) = saved.into_iter().rev().find(|x| *x <= at) {
+        if let Some(graph) = load_saved(generated).await? {
+            return Ok((generated, Arc::new(graph)));
+        }
+    }
+
+    let interval = state
+        .config
+        .graph_interval
+        .unwrap_or(DEFAULT_GRAPH_INTERVAL)
+        .max(1) as i64;
+    let at = at - at.rem_euclid(interval);
+    if let Some(graph) = state.graph.past(at) {
+        return Ok((at, graph));
+    }
+
+    let _building = state.graph.building.lock().await;
+    // Someone else may have built it while this waited
+    if let Some(graph) = state.graph.past(at) {
+        return Ok((at, graph));
+    }
+
+    let graph = Arc::new(build(state, Some(at)).await?);
+    let mut past = state.graph.past.lock().unwrap();
+    past.push_back((at, graph.clone()));
+    if past.len() > PAST_GRAPHS {
+        past.pop_front();
+    }
+    Ok((at, graph))
+}
+
+/// Returns the timestamps of every snapshot on disk, oldest first.
+pub async fn list_saved() -> anyhow::Result<Vec<i64>> {
+    let mut saved = Vec::new();
+
+    let mut dir = match tokio::fs::read_dir(SNAPSHOT_DIR).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(saved),
+        Err(e) => return Err(e.into()),
+    };
+
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name();
+        let generated = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("graph-"))
+            .and_then(|name| name.strip_suffix(".json"))
+            .and_then(|generated| generated.parse::<i64>().ok());
+        if let Some(generated) = generated {
+            saved.push(generated);
+        }
+    }
+
+    saved.sort();
+    Ok(saved)
+}
+
+/// Loads the newest snapshot on disk into memory, so it can be served straight after a restart.
+pub async fn load(state: &AppState) -> anyhow::Result<()> {
+    let Some(generated) = list_saved().await?.pop() else {
+        return Ok(());
+    };
+
+    let json = Bytes::from(tokio::fs::read(snapshot_path(generated)).await?);
+    let graph: Graph = serde_json::from_slice(&json)?;
+    let snapshot = Snapshot::from_parts(generated, graph, json);
+    *state.graph.snapshot.write().await = Some(Arc::new(snapshot));
+
+    println!("Loaded graph snapshot from {}", generated);
+    Ok(())
+}
+
+async fn rebuild(state: &AppState) -> anyhow::Result<()> {
+    state.graph.pending_work.store(0, Ordering::Relaxed);
+
+    let generated = chrono::Utc::now().timestamp();
+    let graph = build(state, None).await?;
+    let snapshot = Snapshot::new(generated, graph)?;
+    save(state, &snapshot).await?;
+
+    println!(
+        "Graph rebuilt with {} domains",
+        snapshot.graph.links_to.len()
+    );
+    *state.graph.snapshot.write().await = Some(Arc::new(snapshot));
+    Ok(())
+}
+
+pub async fn builder(state: AppState) {
+    let interval = state
+        .config
+        .graph_interval
+        .unwrap_or(DEFAULT_GRAPH_INTERVAL);
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(interval));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = state.graph.rebuild.notified() => {
+                interval.reset();
+            },
+        }
+
+        if let Err(e) = rebuild(&state).await {
+            eprintln!("Failed to rebuild graph: {}", e);
+        }
+    }
+}
+
+/// Checks the request's conditional headers against a snapshot.
+fn not_modified(headers: &HeaderMap, etag: &str, generated: i64) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        let if_none_match = if_none_match.to_str().unwrap_or_default();
+        return if_none_match
+            .split(',')
+            .any(|x| x.trim() == etag || x.trim() == "*");
+    }
+
+    if let Some(if_modified_since) = headers.get(header::IF_MODIFIED_SINCE) {
+        let if_modified_since = if_modified_since.to_str().unwrap_or_default();
+        if let Ok(since) = chrono::DateTime::parse_from_rfc2822(if_modified_since) {
+            return generated <= since.timestamp();
+        }
+    }
+
+    false
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GraphQuery {
+    #[serde(default)]
+    format: Format,
+    /// Unix timestamp to show the graph as it was at, using the last snapshot taken by then
+    as_of: Option<i64>,
+}
+
+fn render(graph: &Graph, format: Format) -> anyhow::Result<Body> {
+    Ok(match format {
+        Format::Json => Body::from(serde_json::to_vec(graph)?),
+        Format::GraphMl => Body::from(export::graphml(graph)?),
+        Format::Gexf => Body::from(export::gexf(graph)?),
+        Format::Dot => Body::from(export::dot(graph)?),
+        Format::Csv => Body::from(export::csv(graph)?),
+    })
+}
+
+/// Sets the content type, and has anything other than JSON downloaded as a file.
+fn with_format(response: response::Builder, format: Format) -> response::Builder {
+    let response = response.header(header::CONTENT_TYPE, format.content_type());
+    if format == Format::Json {
+        return response;
+    }
+
+    response.header(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"graph.{}\"", format.extension()),
+    )
+}
+
+pub async fn graph(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Query(query): Query<GraphQuery>,
+    headers: HeaderMap,
+) -> AppResult<Response<Body>> {
+    if !keys::authorize(&state, &token, keys::Scope::Analytics).await? {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    if let Some(as_of) = query.as_of {
+        let (_, graph) = at(&state, as_of).await?;
+        let response = with_format(Response::builder(), query.format);
+        return Ok(response.body(render(&graph, query.format)?)?);
+    }
+
+    // The first snapshot is built in the background on startup
+    let Some(snapshot) = state.graph.latest().await else {
+        return Ok((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "30")],
+            "graph is still being built",
+        )
+            .into_response());
+    };
+
+    let format = query.format;
+    let etag = if format == Format::Json {
+        snapshot.etag.clone()
+    } else {
+        format!(
+            "{}-{}\"",
+            snapshot.etag.trim_end_matches('"'),
+            format.extension()
+        )
+    };
+
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, snapshot.last_modified())
+        .header(header::CACHE_CONTROL, "no-cache");
+
+    if not_modified(&headers, &etag, snapshot.generated) {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())?);
+    }
+
+    let body = match format {
+        Format::Json => Body::from(snapshot.json.clone()),
+        _ => render(&snapshot.graph, format)?,
+    };
+
+    Ok(with_format(response, format).body(body)?)
+}
+
+/// Lists the snapshots that can be passed to `/graph/diff`, oldest first.
+pub async fn snapshots(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+) -> AppResult<Response<Body>> {
+    if !keys::authorize(&state, &token, keys::Scope::Analytics).await? {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    Ok(Json(list_saved().await?).into_response())
+}
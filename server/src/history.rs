@@ -0,0 +1,170 @@
+use crate::storage::{Op, Storage};
+use std::collections::{HashMap, HashSet};
+
+/// When a link between two pages was seen, as stored in its `link:*` hash.
+///
+/// Links recorded before this was tracked have no timestamps, and are treated
+/// as having always been there.
+#[derive(Debug, Clone, Default)]
+pub struct Seen {
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>,
+    /// Set once a scrape of the page no longer finds the link
+    pub removed: Option<i64>,
+    /// Earlier stretches the link was gone for before coming back, as removed and restored times
+    pub removals: Vec<(i64, i64)>,
+}
+
+impl Seen {
+    pub fn from_fields(fields: &HashMap<String, String>) -> Self {
+        let get = |field: &str| fields.get(field).and_then(|x| x.parse::<i64>().ok());
+        Self {
+            first_seen: get("firstSeen"),
+            last_seen: get("lastSeen"),
+            removed: get("removed"),
+            removals: fields
+                .get("removals")
+                .map(|x| parse_removals(x))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether the link was on the page at `as_of`, or is on it now if no time is given.
+    pub fn visible(&self, as_of: Option<i64>) -> bool {
+        match as_of {
+            None => self.removed.is_none(),
+            Some(as_of) => {
+                self.first_seen.is_none_or(|x| x <= as_of)
+                    && self.removed.is_none_or(|x| x > as_of)
+                    && !self
+                        .removals
+                        .iter()
+                        .any(|(removed, restored)| (*removed..*restored).contains(&as_of))
+            }
+        }
+    }
+}
+
+/// Reads the `removals` field, stored as comma-separated `removed-restored` pairs.
+fn parse_removals(removals: &str) -> Vec<(i64, i64)> {
+    removals
+        .split(',')
+        .filter_map(|x| {
+            let (removed, restored) = x.split_once('-')?;
+            Some((removed.parse().ok()?, restored.parse().ok()?))
+        })
+        .collect()
+}
+
+fn format_removals(removals: &[(i64, i64)]) -> String {
+    removals
+        .iter()
+        .map(|(removed, restored)| format!("{}-{}", removed, restored))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub async fn seen(db: &dyn Storage, from: &str, to: &str) -> anyhow::Result<Seen> {
+    let fields = db.hgetall(&format!("link:{}:{}", from, to)).await?;
+    Ok(Seen::from_fields(&fields))
+}
+
+/// The writes for a link that was just found on `from`, alongside its badge.
+///
+/// `seen` is what was known about the link beforehand.
+pub fn seen_ops(
+    from: &str,
+    to: &str,
+    seen: Seen,
+    now: i64,
+    mut fields: HashMap<String, String>,
+) -> Vec<Op> {
+    let key = format!("link:{}:{}", from, to);
+    if seen.first_seen.is_none() {
+        fields.insert("firstSeen".to_string(), now.to_string());
+    }
+    fields.insert("lastSeen".to_string(), now.to_string());
+
+    // Coming back closes off the removal, which is kept so older graphs still leave the link out
+    let mut ops = Vec::new();
+    if let Some(removed) = seen.removed {
+        let mut removals = seen.removals;
+        removals.push((removed, now));
+        fields.insert("removals".to_string(), format_removals(&removals));
+        ops.push(Op::HDel(key.clone(), "removed".to_string()));
+    }
+    ops.push(Op::HSet(key, fields));
+    ops
+}
+
+/// Marks the links `from` used to have that a fresh scrape didn't find.
+///
+/// The links themselves are kept so the graph can still be viewed as it was.
+pub fn removed_ops(
+    from: &str,
+    previous: &HashMap<String, Seen>,
+    found: &HashSet<String>,
+    now: i64,
+) -> Vec<Op> {
+    previous
+        .iter()
+        .filter(|(to, seen)| !found.contains(*to) && seen.removed.is_none())
+        .map(|(to, _)| {
+            Op::HSet(
+                format!("link:{}:{}", from, to),
+                HashMap::from_iter(vec![("removed".to_string(), now.to_string())]),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn visible_follows_removals() {
+        // Found at 10, gone from 20 to 30, and gone again since 40
+        let seen = Seen::from_fields(&HashMap::from_iter(vec![
+            ("firstSeen".to_string(), "10".to_string()),
+            ("lastSeen".to_string(), "35".to_string()),
+            ("removed".to_string(), "40".to_string()),
+            ("removals".to_string(), format_removals(&[(20, 30)])),
+        ]));
+
+        assert!(!seen.visible(None));
+        assert!(!seen.visible(Some(5)));
+        assert!(seen.visible(Some(10)));
+        assert!(seen.visible(Some(19)));
+        assert!(!seen.visible(Some(20)));
+        assert!(!seen.visible(Some(29)));
+        assert!(seen.visible(Some(30)));
+        assert!(!seen.visible(Some(40)));
+    }
+
+    #[test]
+    fn untracked_links_were_always_there() {
+        let seen = Seen::default();
+        assert!(seen.visible(None));
+        assert!(seen.visible(Some(0)));
+    }
+
+    #[test]
+    fn restoring_records_the_removal() {
+        let seen = Seen {
+            first_seen: Some(10),
+            last_seen: Some(15),
+            removed: Some(20),
+            removals: vec![(1, 2)],
+        };
+        let ops = seen_ops("a", "b", seen, 30, HashMap::new());
+        let Some(Op::HSet(_, fields)) = ops.last() else {
+            panic!("expected the link's fields to be set");
+        };
+        assert_eq!(
+            fields.get("removals").map(|x| parse_removals(x)),
+            Some(vec![(1, 2), (20, 30)])
+        );
+        assert!(matches!(&ops[0], Op::HDel(_, field) if field == "removed"));
+    }
+}
@@ -0,0 +1,453 @@
+use crate::{
+    leases,
+    storage::{Op, Storage},
+    AppResult, AppState,
+};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// What an API key is allowed to do. The admin key can do everything.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Claiming and reporting work, and uploading the badges found along the way
+    Scraper,
+    /// Read-only access to the graph and domain details
+    Analytics,
+    /// Uploading badges and nothing else
+    Badge,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Scraper => "scraper",
+            Scope::Analytics => "analytics",
+            Scope::Badge => "badge",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope.trim() {
+            "scraper" => Some(Scope::Scraper),
+            "analytics" => Some(Scope::Analytics),
+            "badge" => Some(Scope::Badge),
+            _ => None,
+        }
+    }
+
+    /// Parses a comma-separated list of scopes, failing on anything unknown.
+    pub fn parse_list(scopes: &str) -> Option<Vec<Self>> {
+        scopes
+            .split(',')
+            .filter(|x| !x.trim().is_empty())
+            .map(Scope::parse)
+            .collect()
+    }
+
+    /// Whether a key with this scope may do something that needs `required`.
+    fn allows(self, required: Scope) -> bool {
+        self == required || (self == Scope::Scraper && required == Scope::Badge)
+    }
+}
+
+fn join_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|x| x.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Hashes a bearer token into its key id, so the tokens themselves are never stored.
+///
+/// The id also identifies the scraper in `inprogress:*`, `leases:owners` and `scraper:leaderboard`.
+pub fn hash(state: &AppState, token: &str) -> String {
+    let mut hasher = Sha256::new();
+    if let Some(pepper) = &state.config.key_pepper {
+        hasher.update(pepper);
+    }
+    hasher.update(token);
+    format!("{:x}", hasher.finalize())
+}
+
+/// An API key, stored as a hash at `auth:keys:{id}` with every id in the `auth:keys` set.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyInfo {
+    pub id: String,
+    pub description: String,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub expired: bool,
+    pub scopes: Vec<Scope>,
+    /// Results posted, from `scraper:leaderboard`
+    pub score: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeyUpdate {
+    description: Option<String>,
+    /// Unix timestamp the key stops working at, or 0 to never expire
+    expires: Option<i64>,
+    scopes: Option<Vec<Scope>>,
+}
+
+/// Stores a key record.
+pub async fn create(
+    db: &dyn Storage,
+    id: &str,
+    description: &str,
+    created: i64,
+    scopes: &[Scope],
+    expires: Option<i64>,
+) -> anyhow::Result<()> {
+    db.apply(create_ops(id, description, created, scopes, expires))
+        .await
+}
+
+/// The writes behind [`create`], for applying alongside others.
+fn create_ops(
+    id: &str,
+    description: &str,
+    created: i64,
+    scopes: &[Scope],
+    expires: Option<i64>,
+) -> Vec<Op> {
+    let mut record = vec![
+        ("description".to_string(), description.to_string()),
+        ("created".to_string(), created.to_string()),
+        ("scopes".to_string(), join_scopes(scopes)),
+    ];
+    if let Some(expires) = expires {
+        record.push(("expires".to_string(), expires.to_string()));
+    }
+
+    vec![
+        Op::HSet(format!("auth:keys:{}", id), HashMap::from_iter(record)),
+        Op::SAdd("auth:keys".to_string(), id.to_string()),
+    ]
+}
+
+/// Whether a member of `auth:keys` is already a key id rather than a plaintext token.
+///
+/// Tokens are UUIDs, so they never look like a hash.
+fn is_key_id(member: &str) -> bool {
+    member.len() == 64 && member.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+async fn info(db: &dyn Storage, id: &str) -> anyhow::Result<Option<KeyInfo>> {
+    let record = db.hgetall(&format!("auth:keys:{}", id)).await?;
+    if record.is_empty() {
+        return Ok(None);
+    }
+
+    let expires = record
+        .get("expires")
+        .and_then(|x| x.parse::<i64>().ok())
+        .filter(|x| *x != 0);
+    let score = db.zscore("scraper:leaderboard", id).await?;
+
+    Ok(Some(KeyInfo {
+        id: id.to_string(),
+        description: record.get("description").cloned().unwrap_or_default(),
+        created: record
+            .get("created")
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(0),
+        expired: expires.is_some_and(|x| x <= chrono::Utc::now().timestamp()),
+        expires,
+        scopes: record
+            .get("scopes")
+            .and_then(|x| Scope::parse_list(x))
+            .unwrap_or_default(),
+        score: score.unwrap_or(0.0) as u64,
+    }))
+}
+
+/// Checks that `token` is the admin key, or an unexpired key with a scope that allows `scope`.
+pub async fn authorize(state: &AppState, token: &str, scope: Scope) -> anyhow::Result<bool> {
+    if token == state.config.admin_key {
+        return Ok(true);
+    }
+
+    let db = &*state.db;
+    let Some(key) = info(db, &hash(state, token)).await? else {
+        return Ok(false);
+    };
+
+    Ok(!key.expired && key.scopes.iter().any(|x| x.allows(scope)))
+}
+
+/// Converts keys from when they were a bare description string into records.
+///
+/// Keys from before scopes existed could do everything a scraper can.
+async fn migrate_records(db: &dyn Storage) -> anyhow::Result<()> {
+    if db.sismember("migrations", "key-records").await? {
+        return Ok(());
+    }
+
+    println!("Converting API keys to records...");
+    let mut count = 0;
+    for key in db.scan("auth:keys:*").await? {
+        let Some(id) = key.strip_prefix("auth:keys:") else {
+            continue;
+        };
+
+        // Anything that isn't a string has already been converted
+        let Ok(Some(description)) = db.get(&key).await else {
+            continue;
+        };
+
+        // Replaced in one go, so a failure can't leave the key missing
+        let mut ops = vec![Op::Del(key.clone())];
+        ops.extend(create_ops(id, &description, 0, &[Scope::Scraper], None));
+        db.apply(ops).await?;
+        count += 1;
+    }
+
+    db.sadd("migrations", "key-records").await?;
+    println!("Converted {} API keys", count);
+    Ok(())
+}
+
+/// Brings API keys stored by older versions up to date.
+pub async fn migrate(state: &AppState) -> anyhow::Result<()> {
+    let db = &*state.db;
+    migrate_records(db).await?;
+    migrate_hashes(db, state).await
+}
+
+/// Replaces plaintext tokens with their hashes everywhere they were stored,
+/// including the admin key's entries.
+async fn migrate_hashes(db: &dyn Storage, state: &AppState) -> anyhow::Result<()> {
+    if db.sismember("migrations", "hashed-keys").await? {
+        return Ok(());
+    }
+
+    println!("Hashing API keys...");
+
+    // Members that are already ids were hashed by a run that stopped partway,
+    // and hashing them again would lock their scrapers out
+    let mut tokens = db
+        .smembers("auth:keys")
+        .await?
+        .into_iter()
+        .filter(|x| !is_key_id(x))
+        .collect::<Vec<_>>();
+    let key_count = tokens.len();
+    tokens.push(state.config.admin_key.clone());
+
+    let owners = db.hgetall("leases:owners").await?;
+
+    // Everything but the key records used the base64 of the token. Each key is
+    // moved over in one go, so a rerun only has whatever's left to do.
+    for token in tokens {
+        let id = hash(state, &token);
+        let old_id = state.base64.encode(token.as_bytes());
+        let mut ops = Vec::new();
+
+        let record = format!("auth:keys:{}", token);
+        let fields = db.hgetall(&record).await?;
+        if !fields.is_empty() {
+            if !db.exists(&format!("auth:keys:{}", id)).await? {
+                ops.push(Op::HSet(format!("auth:keys:{}", id), fields));
+                ops.push(Op::SAdd("auth:keys".to_string(), id.clone()));
+            }
+            ops.push(Op::Del(record));
+        }
+        ops.push(Op::SRem("auth:keys".to_string(), token.clone()));
+
+        let score = db.zscore("scraper:leaderboard", &old_id).await?;
+        if let Some(score) = score {
+            ops.push(Op::ZIncrBy(
+                "scraper:leaderboard".to_string(),
+                score,
+                id.clone(),
+            ));
+            ops.push(Op::ZRem("scraper:leaderboard".to_string(), old_id.clone()));
+        }
+
+        let in_progress = db.smembers(&format!("inprogress:{}", old_id)).await?;
+        for page in &in_progress {
+            ops.push(Op::SAdd(format!("inprogress:{}", id), page.clone()));
+        }
+        if !in_progress.is_empty() {
+            ops.push(Op::Del(format!("inprogress:{}", old_id)));
+        }
+
+        let leased = owners
+            .iter()
+            .filter(|(_, owner)| **owner == old_id)
+            .map(|(page, _)| (page.clone(), id.clone()))
+            .collect::<HashMap<_, _>>();
+        ops.push(Op::HSet("leases:owners".to_string(), leased));
+
+        db.apply(ops).await?;
+    }
+
+    db.sadd("migrations", "hashed-keys").await?;
+    println!("Hashed {} API keys", key_count);
+    Ok(())
+}
+
+pub async fn list(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let db = &*state.db;
+    let mut keys = Vec::new();
+    for id in db.smembers("auth:keys").await? {
+        keys.extend(info(db, &id).await?);
+    }
+    keys.sort_by_key(|x| x.created);
+
+    Ok(Json(keys).into_response())
+}
+
+pub async fn get(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let db = &*state.db;
+    match info(db, &id).await? {
+        Some(key) => Ok(Json(key).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+pub async fn update(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(update): Json<KeyUpdate>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let db = &*state.db;
+    let key = format!("auth:keys:{}", id);
+    if !db.exists(&key).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let mut changes = HashMap::new();
+    if let Some(description) = update.description {
+        changes.insert("description".to_string(), description);
+    }
+    if let Some(scopes) = update.scopes {
+        changes.insert("scopes".to_string(), join_scopes(&scopes));
+    }
+    if let Some(expires) = update.expires {
+        changes.insert("expires".to_string(), expires.to_string());
+    }
+    db.hset(&key, changes).await?;
+
+    match info(db, &id).await? {
+        Some(key) => Ok(Json(key).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+/// Revokes a key straight away, putting anything it had claimed back on the queue.
+pub async fn revoke(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let db = &*state.db;
+    let removed = db.del(&format!("auth:keys:{}", id)).await?;
+    db.srem("auth:keys", &id).await?;
+    if !removed {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let requeued = leases::requeue_owned(db, &state, &id).await?;
+    if requeued > 0 {
+        println!("Requeued {} pages claimed by revoked key {}", requeued, id);
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn migrate_hashes_can_rerun() {
+        let state = AppState::test(serde_json::json!({}));
+        let db = &*state.db;
+        let token = "0b5a5b8e-7d0b-4c8e-9a3e-2f6f4e0c1d2a";
+        let old_id = state.base64.encode(token.as_bytes());
+        let id = hash(&state, token);
+
+        // As stored before keys were hashed
+        create(db, token, "scraper", 0, &[Scope::Scraper], None)
+            .await
+            .unwrap();
+        db.zincrby("scraper:leaderboard", 3.0, &old_id)
+            .await
+            .unwrap();
+        db.sadd(&format!("inprogress:{}", old_id), "page")
+            .await
+            .unwrap();
+        db.hset(
+            "leases:owners",
+            HashMap::from_iter(vec![("page".to_string(), old_id.clone())]),
+        )
+        .await
+        .unwrap();
+
+        // Running again after a run that stopped before marking itself done changes nothing
+        for _ in 0..2 {
+            migrate_hashes(db, &state).await.unwrap();
+            db.srem("migrations", "hashed-keys").await.unwrap();
+
+            assert_eq!(db.smembers("auth:keys").await.unwrap(), vec![id.clone()]);
+            assert!(!db.exists(&format!("auth:keys:{}", token)).await.unwrap());
+            let info = info(db, &id).await.unwrap().unwrap();
+            assert_eq!(info.description, "scraper");
+            assert_eq!(
+                db.zscore("scraper:leaderboard", &id).await.unwrap(),
+                Some(3.0)
+            );
+            assert_eq!(
+                db.zscore("scraper:leaderboard", &old_id).await.unwrap(),
+                None
+            );
+            assert_eq!(
+                db.smembers(&format!("inprogress:{}", id)).await.unwrap(),
+                vec!["page"]
+            );
+            assert_eq!(
+                db.hget("leases:owners", "page").await.unwrap(),
+                Some(id.clone())
+            );
+        }
+    }
+}
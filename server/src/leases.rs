@@ -0,0 +1,298 @@
+use crate::{
+    denylist, get_domain, queue,
+    storage::{Op, Storage},
+    AppResult, AppState,
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use base64::Engine;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// How long a scraper has to report back on a page before it's requeued
+pub const DEFAULT_LEASE_TIMEOUT: u64 = 60 * 10;
+
+/// How often the reaper checks for expired leases
+const REAP_INTERVAL: u64 = 30;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Lease {
+    url: String,
+    deadline: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ScraperLeases {
+    description: Option<String>,
+    leases: Vec<Lease>,
+}
+
+/// Records that `page` has been handed out to the scraper whose key id is `api_key_hash`.
+///
+/// Leases are stored in the `leases` sorted set (scored by deadline), with the owner of
+/// each lease in the `leases:owners` hash and the scraper's `inprogress:*` set.
+pub async fn grant(
+    db: &dyn Storage,
+    state: &AppState,
+    api_key_hash: &str,
+    page: &str,
+) -> anyhow::Result<()> {
+    let timeout = state.config.lease_timeout.unwrap_or(DEFAULT_LEASE_TIMEOUT) as i64;
+    let deadline = chrono::Utc::now().timestamp() + timeout;
+
+    db.apply(vec![
+        Op::ZAdd("leases".to_string(), deadline as f64, page.to_string()),
+        Op::HSet(
+            "leases:owners".to_string(),
+            HashMap::from_iter(vec![(page.to_string(), api_key_hash.to_string())]),
+        ),
+        Op::SAdd(format!("inprogress:{}", api_key_hash), page.to_string()),
+    ])
+    .await
+}
+
+/// The writes that release the lease on `page` once a scraper has reported back on it.
+///
+/// These go in with the result itself, so a result that never lands leaves the
+/// lease in place for the reaper to requeue.
+pub async fn release_ops(
+    db: &dyn Storage,
+    api_key_hash: &str,
+    page: &str,
+) -> anyhow::Result<Vec<Op>> {
+    let owner = db.hget("leases:owners", page).await?;
+
+    // Only drop the lease if it's still ours - it may have expired and been handed to someone else
+    let mut ops = Vec::new();
+    if owner.is_none() || owner.as_deref() == Some(api_key_hash) {
+        ops.push(Op::ZRem("leases".to_string(), page.to_string()));
+        ops.push(Op::HDel("leases:owners".to_string(), page.to_string()));
+    }
+    ops.push(Op::SRem(
+        format!("inprogress:{}", api_key_hash),
+        page.to_string(),
+    ));
+
+    Ok(ops)
+}
+
+/// Whether the scraper whose key id is `api_key_hash` currently holds the lease on `page`.
+pub async fn holds(db: &dyn Storage, api_key_hash: &str, page: &str) -> anyhow::Result<bool> {
+    Ok(db.hget("leases:owners", page).await?.as_deref() == Some(api_key_hash))
+}
+
+/// Drops any lease on `page` without requeueing it, whoever holds it.
+pub async fn revoke(db: &dyn Storage, page: &str) -> anyhow::Result<()> {
+    let owner = db.hget("leases:owners", page).await?;
+    db.zrem("leases", page).await?;
+    db.hdel("leases:owners", page).await?;
+
+    if let Some(owner) = owner {
+        db.srem(&format!("inprogress:{}", owner), page).await?;
+    }
+
+    Ok(())
+}
+
+/// Drops the lease on every page whose domain `matches`, without requeueing any of them.
+pub async fn revoke_where(
+    db: &dyn Storage,
+    state: &AppState,
+    matches: impl Fn(&str) -> bool,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+    for page in db.zrange("leases", 0, -1).await? {
+        let url = String::from_utf8(state.base64.decode(&page)?)?;
+        if get_domain(&url).is_some_and(|x| matches(&x)) {
+            revoke(db, &page).await?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Takes the lease on `page` away and puts the page back on the queue, returning
+/// whether there was a lease to take.
+async fn requeue(db: &dyn Storage, state: &AppState, page: &str) -> anyhow::Result<bool> {
+    // Whoever removes the lease gets to deal with it, in case the scraper reported back just now
+    if !db.zrem("leases", page).await? {
+        return Ok(false);
+    }
+
+    let mut ops = Vec::new();
+    if let Some(owner) = db.hget("leases:owners", page).await? {
+        ops.push(Op::SRem(format!("inprogress:{}", owner), page.to_string()));
+    }
+    ops.push(Op::HDel("leases:owners".to_string(), page.to_string()));
+
+    // The domain may have been denylisted while the page was out
+    let url = String::from_utf8(state.base64.decode(page)?)?;
+    let denylisted = match get_domain(&url) {
+        Some(domain) => denylist::is_denylisted(db, state, &domain).await?,
+        None => false,
+    };
+    if !denylisted {
+        ops.push(queue::enqueue_op(db, page, 0).await?);
+    }
+    db.apply(ops).await?;
+    Ok(true)
+}
+
+/// Puts every page leased to `owner` back on the queue, for when its key is revoked.
+pub async fn requeue_owned(
+    db: &dyn Storage,
+    state: &AppState,
+    owner: &str,
+) -> anyhow::Result<usize> {
+    let mut count = 0;
+    for (page, page_owner) in db.hgetall("leases:owners").await? {
+        if page_owner == owner && requeue(db, state, &page).await? {
+            count += 1;
+        }
+    }
+
+    // Anything left over no longer has a lease behind it
+    db.del(&format!("inprogress:{}", owner)).await?;
+    Ok(count)
+}
+
+/// Puts every page whose lease has expired back on the queue.
+async fn reap(state: &AppState) -> anyhow::Result<usize> {
+    let now = chrono::Utc::now().timestamp();
+
+    let db = &*state.db;
+    let expired = db.zrangebyscore("leases", now as f64, None).await?;
+
+    let mut count = 0;
+    for page in &expired {
+        if requeue(db, state, page).await? {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+pub async fn reaper(state: AppState) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(REAP_INTERVAL));
+    loop {
+        interval.tick().await;
+
+        match reap(&state).await {
+            Ok(0) => {}
+            Ok(count) => println!("Requeued {} expired leases", count),
+            Err(e) => eprintln!("Failed to reap leases: {}", e),
+        }
+    }
+}
+
+pub async fn leases(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let db = &*state.db;
+    let leases = db.zrange_withscores("leases", 0, -1).await?;
+    let owners = db.hgetall("leases:owners").await?;
+
+    let mut result: HashMap<String, ScraperLeases> = HashMap::new();
+    for (page, deadline) in leases {
+        let Some(owner) = owners.get(&page) else {
+            continue;
+        };
+
+        // Owners are key ids, which match up with `/keys`
+        let id = owner.clone();
+        if !result.contains_key(&id) {
+            let description = db.hget(&format!("auth:keys:{}", id), "description").await?;
+            result.insert(
+                id.clone(),
+                ScraperLeases {
+                    description,
+                    leases: Vec::new(),
+                },
+            );
+        }
+
+        let url = String::from_utf8(state.base64.decode(&page)?)?;
+        result.get_mut(&id).unwrap().leases.push(Lease {
+            url,
+            deadline: deadline as i64,
+        });
+    }
+
+    Ok(Json(result).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reap_skips_denylisted_pages() {
+        // Leases run out as soon as they're granted
+        let state = AppState::test(serde_json::json!({ "lease_timeout": 0 }));
+        let db = &*state.db;
+        let kept = state.base64.encode("https://a.com/".as_bytes());
+        let denied = state.base64.encode("https://b.com/".as_bytes());
+        grant(db, &state, "key", &kept).await.unwrap();
+        grant(db, &state, "key", &denied).await.unwrap();
+        db.sadd("domains:denylist", &state.base64.encode("b.com".as_bytes()))
+            .await
+            .unwrap();
+
+        assert_eq!(reap(&state).await.unwrap(), 2);
+        assert_eq!(db.zrange("pages:queue", 0, -1).await.unwrap(), vec![kept]);
+        assert_eq!(db.zcard("leases").await.unwrap(), 0);
+        assert!(db.smembers("inprogress:key").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn revoke_where_only_drops_matching_domains() {
+        let state = AppState::test(serde_json::json!({}));
+        let db = &*state.db;
+        let kept = state.base64.encode("https://a.com/".as_bytes());
+        let denied = state.base64.encode("https://sub.b.com/".as_bytes());
+        grant(db, &state, "key", &kept).await.unwrap();
+        grant(db, &state, "key", &denied).await.unwrap();
+
+        let revoked = revoke_where(db, &state, |x| x.ends_with(".b.com"))
+            .await
+            .unwrap();
+        assert_eq!(revoked, 1);
+        assert!(holds(db, "key", &kept).await.unwrap());
+        assert!(!holds(db, "key", &denied).await.unwrap());
+        assert_eq!(db.smembers("inprogress:key").await.unwrap(), vec![kept]);
+    }
+
+    #[tokio::test]
+    async fn requeue_owned_leaves_other_keys_alone() {
+        let state = AppState::test(serde_json::json!({}));
+        let db = &*state.db;
+        let revoked = state.base64.encode("https://a.com/".as_bytes());
+        let other = state.base64.encode("https://b.com/".as_bytes());
+        grant(db, &state, "revoked", &revoked).await.unwrap();
+        grant(db, &state, "other", &other).await.unwrap();
+
+        assert_eq!(requeue_owned(db, &state, "revoked").await.unwrap(), 1);
+        assert_eq!(
+            db.zrange("pages:queue", 0, -1).await.unwrap(),
+            vec![revoked]
+        );
+        assert!(!db.exists("inprogress:revoked").await.unwrap());
+        assert!(holds(db, "other", &other).await.unwrap());
+        assert_eq!(db.zcard("leases").await.unwrap(), 1);
+    }
+}
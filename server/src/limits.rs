@@ -0,0 +1,127 @@
+use crate::{get_domain, storage::Storage, AppResult, AppState};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// How many pages are recorded per domain when `domains:max_pages` isn't set
+pub const DEFAULT_MAX_PAGES: usize = 100;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    pub max_pages: usize,
+    pub overrides: BTreeMap<String, usize>,
+}
+
+async fn global_max_pages(db: &dyn Storage) -> anyhow::Result<usize> {
+    let max_pages = db.get("domains:max_pages").await?;
+    Ok(max_pages
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(DEFAULT_MAX_PAGES))
+}
+
+/// The most pages to record on `domain`, from its override in
+/// `domains:max_pages:overrides` if it has one.
+pub async fn max_pages(db: &dyn Storage, domain: &str) -> anyhow::Result<usize> {
+    let max_pages = db.hget("domains:max_pages:overrides", domain).await?;
+    match max_pages.and_then(|x| x.parse().ok()) {
+        Some(max_pages) => Ok(max_pages),
+        None => global_max_pages(db).await,
+    }
+}
+
+pub async fn limits(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let db = &*state.db;
+    let overrides = db
+        .hgetall("domains:max_pages:overrides")
+        .await?
+        .into_iter()
+        .filter_map(|(domain, max_pages)| Some((domain, max_pages.parse().ok()?)))
+        .collect();
+
+    Ok(Json(Limits {
+        max_pages: global_max_pages(db).await?,
+        overrides,
+    })
+    .into_response())
+}
+
+pub async fn set_max_pages(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    max_pages: String,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let Ok(max_pages) = max_pages.trim().parse::<usize>() else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let db = &*state.db;
+    db.set("domains:max_pages", &max_pages.to_string(), None)
+        .await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub async fn set_override(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Path(domain): Path<String>,
+    max_pages: String,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let domain = domain.to_lowercase();
+    if get_domain(&format!("https://{}/", domain)).as_deref() != Some(domain.as_str()) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let Ok(max_pages) = max_pages.trim().parse::<usize>() else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let db = &*state.db;
+    db.hset(
+        "domains:max_pages:overrides",
+        HashMap::from_iter(vec![(domain, max_pages.to_string())]),
+    )
+    .await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+pub async fn remove_override(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Path(domain): Path<String>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let db = &*state.db;
+    let removed = db
+        .hdel("domains:max_pages:overrides", &domain.to_lowercase())
+        .await?;
+    if !removed {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
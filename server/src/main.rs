@@ -1,64 +1,129 @@
 use axum::{
     body::{Body, Bytes},
-    extract::{Path, State},
-    http::{Response, StatusCode},
+    extract::{Path, Query, State},
+    http::{header::RETRY_AFTER, Response, StatusCode},
     response::IntoResponse,
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
 use axum_auth::AuthBearer;
 use base64::engine::GeneralPurpose;
 use base64::Engine;
-use fred::{
-    clients::RedisClient,
-    interfaces::{
-        ClientLike, HashesInterface, HyperloglogInterface, KeysInterface, ListInterface,
-        SetsInterface, SortedSetsInterface, TransactionInterface,
-    },
-    types::{Server, ServerConfig},
-};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use storage::{Expiry, Op, Storage, Watch};
 use uuid::Uuid;
 
+mod badge;
+mod denylist;
+mod diff;
+mod domain;
+mod export;
+mod failures;
+mod graph;
+mod history;
+mod keys;
+mod leases;
+mod limits;
+mod optout;
+mod page_graph;
+mod politeness;
+mod purge;
+mod queue;
+mod ratelimit;
+mod recrawl;
+mod search;
+mod storage;
+mod verify;
+
 #[derive(Deserialize, Debug, Clone)]
 struct Config {
     port: u16,
     admin_key: String,
+    /// Mixed into API key hashes. Changing it invalidates every key.
+    key_pepper: Option<String>,
+    storage: Option<storage::Backend>,
+    /// Where the memory backend saves to. Without one, everything is lost on restart.
+    storage_path: Option<String>,
     redis_host: Option<String>,
     redis_port: Option<u16>,
+    /// How many connections to open to Redis, so handlers don't queue up behind each other
+    redis_pool_size: Option<usize>,
+    lease_timeout: Option<u64>,
+    domain_interval: Option<u64>,
+    domain_intervals: Option<HashMap<String, u64>>,
+    recrawl_interval: Option<u64>,
+    /// Seconds to wait before retrying a page that failed, doubled for each failure in a row
+    failure_backoff: Option<u64>,
+    /// How many failures in a row before a page is no longer scraped
+    max_failures: Option<u64>,
+    graph_interval: Option<u64>,
+    graph_rebuild_after: Option<u64>,
+    /// How many graph snapshots to keep on disk for `/graph/diff`
+    graph_snapshots: Option<usize>,
+    rate_limits: Option<ratelimit::RateLimits>,
+    verification: Option<verify::Verification>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct LinkSchema {
     pub to: String,
     pub image: String,
     pub image_hash: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct WorkSchema {
     pub orig_url: String,
     pub result_url: String,
     pub success: bool,
     pub links: Option<Vec<LinkSchema>>,
+    pub crawl_delay: Option<f64>,
+    /// robots.txt doesn't let us scrape the page, which isn't held against it as a failure
+    pub disallowed: Option<bool>,
+    /// Why the scrape failed, if it did
+    pub failure: Option<failures::FailureReason>,
+    pub http_status: Option<u16>,
+    /// Picked by the scraper and kept the same across retries, so a result is only applied once
+    pub submission_id: Option<String>,
 }
 
 #[derive(Clone)]
 struct AppState {
     config: Config,
-    redis: Arc<Mutex<RedisClient>>,
+    db: Arc<dyn Storage>,
     base64: GeneralPurpose,
+    graph: Arc<graph::GraphCache>,
+    limiter: Arc<ratelimit::RateLimiter>,
 }
 
-#[derive(Serialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct Graph {
-    links_to: HashMap<String, Vec<String>>,
-    linked_from: HashMap<String, Vec<String>>,
-    images: HashMap<String, Vec<String>>,
+#[cfg(test)]
+impl AppState {
+    /// A state backed by an empty memory database, with `config` on top of the bare minimum.
+    fn test(config: serde_json::Value) -> Self {
+        let mut base = serde_json::json!({
+            "port": 0,
+            "admin_key": "admin",
+            "storage": "memory",
+        });
+        base.as_object_mut()
+            .unwrap()
+            .extend(config.as_object().cloned().unwrap_or_default());
+
+        AppState {
+            config: serde_json::from_value(base).unwrap(),
+            db: Arc::new(storage::MemoryStorage::open(None).unwrap()),
+            base64: base64::prelude::BASE64_STANDARD,
+            graph: Arc::new(graph::GraphCache::default()),
+            limiter: Arc::new(ratelimit::RateLimiter::default()),
+        }
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -91,11 +156,37 @@ fn get_domain(url: &str) -> Option<String> {
     Some(domain.to_string())
 }
 
-async fn auth_valid(redis: Arc<Mutex<RedisClient>>, token: String) -> anyhow::Result<bool> {
-    let redis = redis.lock().await;
-    let key = format!("auth:keys:{}", token);
-    let exists: bool = redis.exists(&key).await?;
-    Ok(exists)
+/// Follows a page's redirect if it has one, returning the decoded URL.
+async fn resolve_page(db: &dyn Storage, state: &AppState, page: &str) -> anyhow::Result<String> {
+    let redirect = db.get(&format!("redirect:{}", page)).await.unwrap_or(None);
+    let page = redirect.as_deref().unwrap_or(page);
+    Ok(String::from_utf8(state.base64.decode(page)?)?)
+}
+
+/// Notes a live link from `from` to `to` being gained or lost, by the pages' domains.
+async fn count_link(
+    db: &dyn Storage,
+    state: &AppState,
+    changes: &mut HashMap<(String, String), i64>,
+    from: &str,
+    to: &str,
+    change: i64,
+) -> anyhow::Result<()> {
+    let from = get_domain(&resolve_page(db, state, from).await?);
+    let to = get_domain(&resolve_page(db, state, to).await?);
+    if let (Some(from), Some(to)) = (from, to) {
+        *changes.entry((from, to)).or_default() += change;
+    }
+    Ok(())
+}
+
+/// Hash the API key so it's identifiable if you know the key,
+/// but otherwise anonymous
+fn anonymize_key(state: &AppState, api_key_hash: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(api_key_hash);
+    let hash = hasher.finalize();
+    state.base64.encode(hash)
 }
 
 fn url_valid(url: &str) -> bool {
@@ -113,41 +204,186 @@ fn url_valid(url: &str) -> bool {
     true
 }
 
+#[derive(Deserialize, Debug)]
+struct CreateAccountQuery {
+    /// Comma-separated, defaults to `scraper`
+    scopes: Option<String>,
+    /// Unix timestamp the key stops working at
+    expires: Option<i64>,
+}
+
 async fn create_account(
     AuthBearer(token): AuthBearer,
     State(state): State<AppState>,
+    Query(query): Query<CreateAccountQuery>,
     desc: String,
 ) -> AppResult<Response<axum::body::Body>> {
     if token != state.config.admin_key {
         return Ok(StatusCode::UNAUTHORIZED.into_response());
     }
 
+    let scopes = match query.scopes {
+        Some(scopes) => keys::Scope::parse_list(&scopes),
+        None => Some(vec![keys::Scope::Scraper]),
+    };
+    let Some(scopes) = scopes.filter(|x| !x.is_empty()) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
     let key = Uuid::new_v4();
-    let redis = state.redis.lock().await;
-    redis
-        .set(&format!("auth:keys:{}", key), &desc, None, None, false)
-        .await?;
+    let db = &*state.db;
+    let now = chrono::Utc::now().timestamp();
+    let id = keys::hash(&state, &key.to_string());
+    keys::create(db, &id, &desc, now, &scopes, query.expires).await?;
 
     Ok(Response::new(key.to_string().into()))
 }
 
+/// The most URLs a scraper can claim or submit in a single request
+const MAX_WORK_BATCH: usize = 100;
+
+#[derive(Deserialize, Debug)]
+struct WorkQuery {
+    count: Option<usize>,
+}
+
+/// How many queued pages to look at per round trip when claiming work
+const CLAIM_WINDOW: usize = 100;
+
+/// How far down the queue a single claim will look for a domain that isn't cooling down
+const MAX_CLAIM_SCAN: usize = 1000;
+
+/// What came of trying to claim a page.
+enum Claim {
+    Work(String),
+    /// Nothing is queued
+    Empty,
+    /// Everything within reach is on a domain that's cooling down, so try again after this long
+    Wait(Duration),
+}
+
+/// Takes the highest-priority page off the queue and leases it to the scraper.
+///
+/// Pages on domains that were handed out too recently are skipped over and
+/// left in the queue.
+async fn claim_work(
+    state: &AppState,
+    db: &dyn Storage,
+    api_key_hash: &str,
+) -> anyhow::Result<Claim> {
+    if let Some(url) = verify::claim(state, db, api_key_hash).await? {
+        return Ok(Claim::Work(url));
+    }
+
+    let mut cooling = HashSet::new();
+    let mut wait: Option<Duration> = None;
+    let mut offset = 0;
+
+    while offset < MAX_CLAIM_SCAN {
+        let window = queue::peek(db, offset, CLAIM_WINDOW).await?;
+        if window.is_empty() {
+            break;
+        }
+
+        for work in &window {
+            let url = String::from_utf8(state.base64.decode(work.as_bytes())?)?;
+            let domain = get_domain(&url);
+            if let Some(domain) = &domain {
+                if cooling.contains(domain) {
+                    continue;
+                }
+
+                if let Some(cooldown) = politeness::cooldown(db, state, domain).await? {
+                    wait = Some(wait.map_or(cooldown, |x| x.min(cooldown)));
+                    cooling.insert(domain.clone());
+                    continue;
+                }
+            }
+
+            if !db.zrem("pages:queue", work).await? {
+                continue;
+            }
+
+            // Another claim may have started the domain's cooldown since it was checked
+            if let Some(domain) = domain {
+                if !politeness::try_acquire(db, state, &domain).await? {
+                    queue::enqueue(db, work).await?;
+                    cooling.insert(domain);
+                    continue;
+                }
+            }
+
+            leases::grant(db, state, api_key_hash, work).await?;
+            return Ok(Claim::Work(url));
+        }
+
+        offset += window.len();
+    }
+
+    // Pages were skipped rather than there being none, so there will be work once a cooldown ends
+    if !cooling.is_empty() {
+        return Ok(Claim::Wait(wait.unwrap_or(Duration::from_secs(1))));
+    }
+
+    Ok(Claim::Empty)
+}
+
 async fn get_work(
     AuthBearer(token): AuthBearer,
     State(state): State<AppState>,
+    Query(query): Query<WorkQuery>,
 ) -> AppResult<Response<Body>> {
-    if !auth_valid(state.redis.clone(), token.clone()).await? && token != state.config.admin_key {
+    if !keys::authorize(&state, &token, keys::Scope::Scraper).await? {
         return Ok(StatusCode::UNAUTHORIZED.into_response());
     }
 
-    let redis = state.redis.lock().await;
-    let work: Option<String> = redis.lpop("pages:queue", None).await?;
-    if let Some(work) = work {
-        let api_key_hash = state.base64.encode(token.as_bytes());
-        redis
-            .sadd(format!("inprogress:{}", api_key_hash), &[work.clone()])
-            .await?;
+    let api_key_hash = keys::hash(&state, &token);
+    let wanted = query.count.map_or(1, |x| x.min(MAX_WORK_BATCH));
+    let (allowed, retry_after) = state.limiter.take(
+        &state,
+        &api_key_hash,
+        ratelimit::Kind::WorkClaims,
+        wanted as u64,
+    );
+    if let Some(retry_after) = retry_after {
+        return Ok(ratelimit::too_many_requests(retry_after));
+    }
+
+    let db = &*state.db;
+    let mut work = Vec::new();
+    let mut wait = None;
+    for _ in 0..allowed {
+        match claim_work(&state, db, &api_key_hash).await? {
+            Claim::Work(url) => work.push(url),
+            Claim::Empty => break,
+            Claim::Wait(retry_after) => {
+                wait = Some(retry_after);
+                break;
+            }
+        }
+    }
+    state.limiter.refund(
+        &state,
+        &api_key_hash,
+        ratelimit::Kind::WorkClaims,
+        allowed - work.len() as u64,
+    );
+
+    // Nothing could be claimed yet, so say when there will be rather than that there's none
+    if let Some(wait) = wait.filter(|_| work.is_empty()) {
+        return Ok((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, wait.as_secs().max(1).to_string())],
+        )
+            .into_response());
+    }
 
-        let work = state.base64.decode(work.as_bytes())?;
+    // Batch requests get a JSON array, single requests get the bare URL
+    if query.count.is_some() {
+        return Ok(Json(work).into_response());
+    }
+
+    if let Some(work) = work.pop() {
         return Ok(Response::new(work.into()));
     }
 
@@ -159,242 +395,618 @@ async fn post_work(
     State(state): State<AppState>,
     Json(work): Json<WorkSchema>,
 ) -> AppResult<Response<Body>> {
-    if !auth_valid(state.redis.clone(), token.clone()).await? && token != state.config.admin_key {
+    if !keys::authorize(&state, &token, keys::Scope::Scraper).await? {
         return Ok(StatusCode::UNAUTHORIZED.into_response());
     }
 
-    let orig_url = state.base64.encode(work.orig_url.as_bytes());
-    let result_url = state.base64.encode(work.result_url.as_bytes());
+    let api_key_hash = keys::hash(&state, &token);
+    if let Err(retry_after) =
+        state
+            .limiter
+            .take_all(&state, &api_key_hash, ratelimit::Kind::WorkPosts, 1)
+    {
+        return Ok(ratelimit::too_many_requests(retry_after));
+    }
 
-    let redis = state.redis.lock().await;
-    let max_pages: usize = redis
-        .get::<String, _>("domains:max_pages")
-        .await
-        .unwrap_or("100".to_string())
-        .parse()
-        .unwrap_or(100);
+    let db = &*state.db;
+    let status = process_work(&state, db, &api_key_hash, work).await?;
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        return Ok(submission_in_flight());
+    }
+    Ok(status.into_response())
+}
+
+async fn post_work_batch(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Json(work): Json<Vec<WorkSchema>>,
+) -> AppResult<Response<Body>> {
+    if !keys::authorize(&state, &token, keys::Scope::Scraper).await? {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    if work.len() > MAX_WORK_BATCH {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+
+    let api_key_hash = keys::hash(&state, &token);
+    if let Err(retry_after) = state.limiter.take_all(
+        &state,
+        &api_key_hash,
+        ratelimit::Kind::WorkPosts,
+        work.len() as u64,
+    ) {
+        return Ok(ratelimit::too_many_requests(retry_after));
+    }
+
+    let db = &*state.db;
+    let mut in_flight = false;
+    for work in work {
+        // Invalid entries are skipped rather than failing the rest of the batch
+        let orig_url = work.orig_url.clone();
+        let status = process_work(&state, db, &api_key_hash, work).await?;
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            in_flight = true;
+        } else if status != StatusCode::NO_CONTENT {
+            eprintln!("Rejected batch entry {}: {}", orig_url, status);
+        }
+    }
+
+    // The rest of the batch is applied, so a retry only has the in-flight entries left to do
+    if in_flight {
+        return Ok(submission_in_flight());
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
 
-    // remove from the client's in-progress tracking
-    let api_key_hash = state.base64.encode(token.as_bytes());
-    redis
-        .srem(format!("inprogress:{}", api_key_hash), &[orig_url.clone()])
-        .await?;
+/// How long a submission is remembered for, so retries of it are ignored
+const SUBMISSION_TTL: i64 = 60 * 60 * 24;
+
+/// How long a submission is held while it's being applied, in case the server dies partway
+const SUBMISSION_CLAIM_TTL: i64 = 60;
+
+/// How long a scraper is told to wait before retrying a submission that's still being applied
+const SUBMISSION_RETRY_AFTER: u64 = 5;
+
+/// How many times a result is read and written again when the pages it touches change meanwhile
+const COMMIT_ATTEMPTS: usize = 5;
+
+/// Sent back when a retried submission is still being applied, so the scraper keeps retrying
+/// until it's either applied or given up on
+fn submission_in_flight() -> Response<Body> {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, SUBMISSION_RETRY_AFTER.to_string())],
+    )
+        .into_response()
+}
+
+fn submission_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn submission_key(api_key_hash: &str, submission_id: &str) -> String {
+    format!("submissions:{}:{}", api_key_hash, submission_id)
+}
+
+/// Marks a submission as applied.
+fn submission_op(api_key_hash: &str, submission_id: &str) -> Op {
+    Op::Set(
+        submission_key(api_key_hash, submission_id),
+        "1".to_string(),
+        Some(Expiry::Seconds(SUBMISSION_TTL)),
+    )
+}
+
+/// Checks a single scraper result and applies it to the database.
+async fn process_work(
+    state: &AppState,
+    db: &dyn Storage,
+    api_key_hash: &str,
+    work: WorkSchema,
+) -> anyhow::Result<StatusCode> {
+    if work
+        .submission_id
+        .as_deref()
+        .is_some_and(|x| !submission_valid(x))
+    {
+        return Ok(StatusCode::BAD_REQUEST);
+    }
+
+    let orig_url = state.base64.encode(work.orig_url.as_bytes());
 
     if !url_valid(&work.orig_url) || !url_valid(&work.result_url) {
-        return Ok(StatusCode::BAD_REQUEST.into_response());
+        return Ok(StatusCode::BAD_REQUEST);
     }
 
-    let result_domain = get_domain(&work.result_url);
-    if result_domain.is_none() {
-        return Ok(StatusCode::BAD_REQUEST.into_response());
+    if get_domain(&work.result_url).is_none() {
+        return Ok(StatusCode::BAD_REQUEST);
+    }
+
+    // A page claimed before its domain opted out (or was denylisted) mustn't bring it back
+    for url in [&work.orig_url, &work.result_url] {
+        let Some(domain) = get_domain(url) else {
+            continue;
+        };
+        if denylist::is_denylisted(db, state, &domain).await? {
+            db.apply(leases::release_ops(db, api_key_hash, &orig_url).await?)
+                .await?;
+            return Ok(StatusCode::CONFLICT);
+        }
+    }
+
+    let disallowed = work.disallowed == Some(true);
+    if disallowed && work.orig_url != work.result_url {
+        return Ok(StatusCode::BAD_REQUEST);
+    }
+
+    // Hold the submission while it's applied, so a retry arriving meanwhile (or after
+    // it's been applied) is turned away rather than applied twice
+    let submission = work
+        .submission_id
+        .as_deref()
+        .map(|x| submission_key(api_key_hash, x));
+    if let Some(key) = &submission {
+        if !db
+            .set_nx(key, "0", Expiry::Seconds(SUBMISSION_CLAIM_TTL))
+            .await?
+        {
+            // Only an applied submission is safe to acknowledge - one that's still in
+            // flight may yet fail, and then the scraper needs to send it again
+            if db.get(key).await?.as_deref() == Some("1") {
+                return Ok(StatusCode::NO_CONTENT);
+            }
+            return Ok(StatusCode::SERVICE_UNAVAILABLE);
+        }
+    }
+
+    let result = apply_checked(state, db, api_key_hash, &orig_url, disallowed, work).await;
+    if let (Err(_) | Ok(StatusCode::CONFLICT), Some(key)) = (&result, &submission) {
+        // Let the scraper's retry through
+        db.del(key).await?;
+    }
+    result
+}
+
+/// Checks a claimed submission against the page's lease and applies it.
+async fn apply_checked(
+    state: &AppState,
+    db: &dyn Storage,
+    api_key_hash: &str,
+    orig_url: &str,
+    disallowed: bool,
+    work: WorkSchema,
+) -> anyhow::Result<StatusCode> {
+    // Being turned away can't take a page off the queue unless we handed it out to this scraper
+    if disallowed
+        && (!db.sismember("pages", orig_url).await?
+            || !leases::holds(db, api_key_hash, orig_url).await?)
+    {
+        return Ok(StatusCode::CONFLICT);
+    }
+
+    // Removed from the client's in-progress tracking along with the result
+    let release = leases::release_ops(db, api_key_hash, orig_url).await?;
+    apply_work(state, db, api_key_hash, work, release).await
+}
+
+/// Commits a result, or holds it for verification.
+async fn apply_work(
+    state: &AppState,
+    db: &dyn Storage,
+    api_key_hash: &str,
+    work: WorkSchema,
+    mut release: Vec<Op>,
+) -> anyhow::Result<StatusCode> {
+    // Held results are committed later, once a second scraper agrees with them
+    if let Some(status) = verify::intercept(state, db, api_key_hash, &work, &mut release).await? {
+        if let Some(submission_id) = &work.submission_id {
+            release.push(submission_op(api_key_hash, submission_id));
+        }
+        db.apply(release).await?;
+        return Ok(status);
+    }
+
+    commit_work(state, db, api_key_hash, work, release, None).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Writes a validated result into the database.
+///
+/// Everything is read up front and then written in one go, along with `release`
+/// (the scraper's lease on the page), so a failure partway through can't leave
+/// the graph half-updated or the page stranded. If another result for the same
+/// pages lands in between, it's all read again rather than overwriting that one.
+///
+/// When `requires` is given, the result is only written while that key exists.
+/// Returns whether it was written.
+async fn commit_work(
+    state: &AppState,
+    db: &dyn Storage,
+    api_key_hash: &str,
+    work: WorkSchema,
+    release: Vec<Op>,
+    requires: Option<&str>,
+) -> anyhow::Result<bool> {
+    for _ in 0..COMMIT_ATTEMPTS {
+        let mut watch = db.watch().await?;
+        if let Some(key) = requires {
+            watch.watch(&[key.to_string()]).await?;
+            if !db.exists(key).await? {
+                return Ok(false);
+            }
+        }
+
+        let (ops, link_changes) =
+            commit_ops(state, db, &mut *watch, api_key_hash, &work, release.clone()).await?;
+        if watch.apply(ops).await? {
+            search::settle_link_counts(db, state, &link_changes).await?;
+            state.graph.record_work(state);
+            println!("Processed {}", work.result_url);
+            return Ok(true);
+        }
+    }
+
+    anyhow::bail!(
+        "{} kept changing while its result was being committed",
+        work.result_url
+    )
+}
+
+/// The writes for [`commit_work`], watching everything they're based on in `watch`.
+///
+/// Also returns how the live links between domains changed, for [`search::settle_link_counts`].
+async fn commit_ops(
+    state: &AppState,
+    db: &dyn Storage,
+    watch: &mut dyn Watch,
+    api_key_hash: &str,
+    work: &WorkSchema,
+    release: Vec<Op>,
+) -> anyhow::Result<(Vec<Op>, HashMap<(String, String), i64>)> {
+    let orig_url = state.base64.encode(work.orig_url.as_bytes());
+    let result_url = state.base64.encode(work.result_url.as_bytes());
+    let result_domain = get_domain(&work.result_url).unwrap();
+    let now = chrono::Utc::now().timestamp();
+    let mut ops = release;
+
+    // Being turned away by robots.txt isn't a scrape, so none of the below applies
+    if work.disallowed == Some(true) {
+        ops.extend(politeness::disallow_ops(
+            state,
+            &result_url,
+            &result_domain,
+            now,
+        ));
+        if let Some(submission_id) = &work.submission_id {
+            ops.push(submission_op(api_key_hash, submission_id));
+        }
+        return Ok((ops, HashMap::new()));
+    }
+
+    // Every result for a page rewrites its data, so that stands in for its links too
+    let mut watched = vec![
+        format!("pages:data:{}", result_url),
+        format!("pages:linksto:{}", result_url),
+    ];
+    if orig_url != result_url {
+        watched.push(format!("pages:data:{}", orig_url));
+        watched.push(format!("pages:linksto:{}", orig_url));
+        watched.push(format!("pages:linkedfrom:{}", orig_url));
+    }
+    watch.watch(&watched).await?;
+
+    // What the page linked to before this scrape, to spot links that have gone
+    let mut previous_links = HashMap::new();
+    for link_to in db
+        .smembers(&format!("pages:linksto:{}", result_url))
+        .await
+        .unwrap_or_default()
+    {
+        let seen = history::seen(db, &result_url, &link_to).await?;
+        previous_links.insert(link_to, seen);
+    }
+    let mut live_links = previous_links
+        .iter()
+        .filter(|(_, seen)| seen.visible(None))
+        .map(|(link_to, _)| link_to.clone())
+        .collect::<HashSet<_>>();
+
+    // Live links gained and lost between pairs of domains, for inbound counts in search
+    let mut link_changes: HashMap<(String, String), i64> = HashMap::new();
+
+    if let Some(crawl_delay) = work.crawl_delay {
+        ops.extend(politeness::crawl_delay_op(
+            state,
+            &result_domain,
+            crawl_delay,
+        ));
     }
 
     if work.orig_url != work.result_url {
         // Update redirect table
-        redis
-            .set(
-                format!("redirect:{}", orig_url),
-                result_url.clone(),
-                None,
-                None,
-                false,
-            )
-            .await?;
+        ops.push(Op::Set(
+            format!("redirect:{}", orig_url),
+            result_url.clone(),
+            None,
+        ));
+        ops.extend(domain::redirect_ops(
+            state,
+            &work.orig_url,
+            &work.result_url,
+        ));
 
         // Merge page record
-        let orig_data = redis
-            .hgetall::<HashMap<String, String>, _>(format!("pages:data:{}", orig_url))
-            .await
-            .ok();
+        let orig_data = db.hgetall(&format!("pages:data:{}", orig_url)).await.ok();
 
         if let Some(orig_data) = orig_data {
-            redis
-                .hset(
-                    format!("pages:data:{}", result_url),
-                    orig_data
-                        .into_iter()
-                        .map(|(k, v)| (k, v.to_string()))
-                        .collect::<HashMap<String, String>>(),
-                )
-                .await?;
-
-            redis.del(format!("pages:data:{}", orig_url)).await?;
+            ops.push(Op::HSet(format!("pages:data:{}", result_url), orig_data));
+            ops.push(Op::Del(format!("pages:data:{}", orig_url)));
         }
 
         // Update link information
-        let links_to = redis
-            .smembers::<Vec<String>, _>(format!("pages:linksto:{}", orig_url))
+        let links_to = db
+            .smembers(&format!("pages:linksto:{}", orig_url))
             .await
             .unwrap_or_default();
         for link_to in links_to {
-            let orig_link_data = redis
-                .hgetall::<HashMap<String, String>, _>(format!("link:{}:{}", orig_url, link_to))
+            let orig_link_data = db
+                .hgetall(&format!("link:{}:{}", orig_url, link_to))
                 .await
                 .ok();
             if let Some(orig_link_data) = orig_link_data {
-                redis
-                    .hset(
-                        format!("link:{}:{}", result_url, link_to),
-                        orig_link_data
-                            .into_iter()
-                            .map(|(k, v)| (k, v.to_string()))
-                            .collect::<HashMap<String, String>>(),
-                    )
-                    .await?;
+                let seen = history::Seen::from_fields(&orig_link_data);
+                if seen.visible(None) {
+                    // The link moves over to the page it redirects to, which may be on another domain
+                    count_link(db, state, &mut link_changes, &orig_url, &link_to, -1).await?;
+                    if live_links.insert(link_to.clone()) {
+                        count_link(db, state, &mut link_changes, &result_url, &link_to, 1).await?;
+                    }
+                }
+                previous_links.entry(link_to.clone()).or_insert(seen);
+                ops.push(Op::HSet(
+                    format!("link:{}:{}", result_url, link_to),
+                    orig_link_data,
+                ));
             }
+
+            // Keep the link's history under the page it redirects to
+            ops.push(Op::SAdd(
+                format!("pages:linksto:{}", result_url),
+                link_to.clone(),
+            ));
+            ops.push(Op::SRem(
+                format!("pages:linkedfrom:{}", link_to),
+                orig_url.clone(),
+            ));
+            ops.push(Op::SAdd(
+                format!("pages:linkedfrom:{}", link_to),
+                result_url.clone(),
+            ));
         }
-        redis.del(format!("pages:linksto:{}", orig_url)).await?;
+        ops.push(Op::Del(format!("pages:linksto:{}", orig_url)));
 
-        let linked_from = redis
-            .smembers::<Vec<String>, _>(format!("pages:linkedfrom:{}", orig_url))
+        let linked_from = db
+            .smembers(&format!("pages:linkedfrom:{}", orig_url))
             .await
             .unwrap_or_default();
+        let link_keys = linked_from
+            .iter()
+            .map(|link_from| format!("link:{}:{}", link_from, orig_url))
+            .collect::<Vec<_>>();
+        watch.watch(&link_keys).await?;
         for link_from in &linked_from {
-            let orig_link_data = redis
-                .hgetall::<HashMap<String, String>, _>(format!("link:{}:{}", link_from, orig_url))
+            let orig_link_data = db
+                .hgetall(&format!("link:{}:{}", link_from, orig_url))
                 .await
                 .ok();
             if let Some(orig_link_data) = orig_link_data {
-                redis
-                    .hset(
-                        format!("link:{}:{}", link_from, result_url),
-                        orig_link_data
-                            .into_iter()
-                            .map(|(k, v)| (k, v.to_string()))
-                            .collect::<HashMap<String, String>>(),
-                    )
-                    .await?;
+                // Links to the page now lead to wherever it redirects to
+                if history::Seen::from_fields(&orig_link_data).visible(None) {
+                    count_link(db, state, &mut link_changes, link_from, &orig_url, -1).await?;
+                    count_link(db, state, &mut link_changes, link_from, &result_url, 1).await?;
+                }
+                ops.push(Op::HSet(
+                    format!("link:{}:{}", link_from, result_url),
+                    orig_link_data,
+                ));
             }
         }
-        redis.del(format!("pages:linkedfrom:{}", orig_url)).await?;
+        ops.push(Op::Del(format!("pages:linkedfrom:{}", orig_url)));
 
-        if !linked_from.is_empty() {
-            redis
-                .sadd(format!("pages:linkedfrom:{}", result_url), linked_from)
-                .await?;
+        for link_from in linked_from {
+            ops.push(Op::SAdd(
+                format!("pages:linkedfrom:{}", result_url),
+                link_from,
+            ));
         }
 
         // Update page sets
-        redis.sadd("pages", &[result_url.clone()]).await?;
-        redis.sadd("pages:visited", &[result_url.clone()]).await?;
-        redis.srem("pages", &[orig_url.clone()]).await?;
-        redis.srem("pages:visited", &[orig_url.clone()]).await?;
+        ops.push(Op::SAdd("pages".to_string(), result_url.clone()));
+        ops.push(Op::SAdd("pages:visited".to_string(), result_url.clone()));
+        ops.push(Op::SRem("pages".to_string(), orig_url.clone()));
+        ops.push(Op::SRem("pages:visited".to_string(), orig_url.clone()));
+        ops.push(Op::SRem("pages:dead".to_string(), orig_url.clone()));
+        ops.push(recrawl::unschedule_op(&orig_url));
     } else {
-        redis.del(format!("redirect:{}", orig_url)).await?;
+        ops.push(Op::Del(format!("redirect:{}", orig_url)));
     }
 
+    // Manual boosts only last until the page has been scraped
+    ops.push(Op::HDel(
+        format!("pages:data:{}", result_url),
+        "boost".to_string(),
+    ));
+
     // Update the page metadata
-    redis
-        .hset(
-            format!("pages:data:{}", result_url),
-            HashMap::from_iter(vec![(
-                "lastScraped".to_string(),
-                chrono::Utc::now().timestamp().to_string(),
-            )]),
-        )
-        .await?;
+    ops.push(Op::HSet(
+        format!("pages:data:{}", result_url),
+        HashMap::from_iter(vec![("lastScraped".to_string(), now.to_string())]),
+    ));
+
+    ops.push(search::index_domain_op(&result_domain));
 
     if work.success {
-        redis.sadd("pages:visited", &[result_url.clone()]).await?;
+        ops.push(Op::SAdd("pages:visited".to_string(), result_url.clone()));
+        ops.extend(failures::success_ops(&result_url));
+        ops.push(politeness::allow_op(state, &result_url, &result_domain));
+        ops.push(recrawl::schedule_op(
+            &result_url,
+            now + recrawl::recrawl_interval(state),
+        ));
     } else {
-        redis.sadd("pages:failed", &[result_url.clone()]).await?;
+        let previous = failures::failures(db, &result_url).await?;
+        ops.extend(failures::failure_ops(
+            state,
+            &result_url,
+            &result_domain,
+            previous,
+            work.failure,
+            work.http_status,
+            now,
+        ));
     }
 
+    // Nothing below is written until the end, so keep track of what this result adds
+    let mut domain_pages: HashMap<String, usize> = HashMap::new();
+    let mut new_pages = HashSet::new();
+    let mut indexed_domains = HashSet::new();
+
     // Discover links
-    if let Some(links) = work.links {
+    if let Some(links) = &work.links {
+        // Links still on the page, even ones that aren't recorded again below
+        let found = links
+            .iter()
+            .filter(|link| url_valid(&link.to))
+            .map(|link| state.base64.encode(link.to.as_bytes()))
+            .collect::<HashSet<_>>();
+        if work.success {
+            ops.extend(history::removed_ops(
+                &result_url,
+                &previous_links,
+                &found,
+                now,
+            ));
+            for link_to in live_links.iter().filter(|x| !found.contains(*x)) {
+                count_link(db, state, &mut link_changes, &result_url, link_to, -1).await?;
+            }
+        }
+
         for link in links {
             if !url_valid(&link.to) || !url_valid(&link.image) {
                 continue;
             }
 
             let to = state.base64.encode(link.to.as_bytes());
-            let to_domain = get_domain(&link.to);
-            if to_domain.is_none() {
+            let to_domain_name = get_domain(&link.to);
+            if to_domain_name.is_none() {
                 continue;
             }
-            let to_domain = state.base64.encode(to_domain.unwrap().as_bytes());
+            let to_domain_name = to_domain_name.unwrap();
+            let to_domain = state.base64.encode(to_domain_name.as_bytes());
 
             // Handle denylisting and page-count limits
-            if redis
-                .sismember("domains:denylist", to_domain.clone())
-                .await?
-            {
+            if denylist::is_denylisted(db, state, &to_domain_name).await? {
                 continue;
             }
 
-            let pages = redis
-                .pfcount::<usize, _>(format!("domain:pages:{}", to_domain.clone()))
-                .await?;
-            if pages >= max_pages {
+            let pages = match domain_pages.get(&to_domain) {
+                Some(pages) => *pages,
+                None => db.pfcount(&format!("domain:pages:{}", to_domain)).await?,
+            };
+            if pages >= limits::max_pages(db, &to_domain_name).await? {
                 continue;
             }
 
-            redis
-                .pfadd(format!("domain:pages:{}", to_domain.clone()), &[to.clone()])
-                .await?;
+            // Add link to the known pages and the queue if it doesn't exist yet
+            // TODO: this should also consider if the link querying is expired
+            let exists = new_pages.contains(&to) || db.sismember("pages", &to).await?;
+            domain_pages.insert(to_domain.clone(), pages + usize::from(!exists));
 
-            // Update link metadata
-            redis
-                .sadd(format!("pages:linksto:{}", result_url), &[to.clone()])
-                .await?;
+            ops.push(Op::PfAdd(format!("domain:pages:{}", to_domain), to.clone()));
 
-            redis
-                .sadd(format!("pages:linkedfrom:{}", to), &[result_url.clone()])
-                .await?;
+            // Update link metadata
+            ops.push(Op::SAdd(
+                format!("pages:linksto:{}", result_url),
+                to.clone(),
+            ));
+            ops.push(Op::SAdd(
+                format!("pages:linkedfrom:{}", to),
+                result_url.clone(),
+            ));
 
             let image_url = state.base64.encode(link.image.as_bytes());
-            redis
-                .hset(
-                    format!("link:{}:{}", result_url, to),
-                    HashMap::from_iter(vec![
-                        ("imageUrl".to_string(), image_url),
-                        ("imageHash".to_string(), link.image_hash),
-                    ]),
-                )
-                .await?;
+            ops.extend(history::seen_ops(
+                &result_url,
+                &to,
+                previous_links.get(&to).cloned().unwrap_or_default(),
+                now,
+                HashMap::from_iter(vec![
+                    ("imageUrl".to_string(), image_url),
+                    ("imageHash".to_string(), link.image_hash.clone()),
+                ]),
+            ));
+            if live_links.insert(to.clone()) {
+                count_link(db, state, &mut link_changes, &result_url, &to, 1).await?;
+            }
+            if indexed_domains.insert(to_domain_name.clone()) {
+                ops.push(search::index_domain_op(&to_domain_name));
+            }
 
-            // Add link to the known pages and the queue if it doesn't exist yet
-            // TODO: this should also consider if the link querying is expired
-            let exists: bool = redis.sismember("pages", &to).await?;
             if !exists {
-                redis.sadd("pages", &[to.clone()]).await?;
-                redis
-                    .hset(
-                        format!("pages:data:{}", to),
-                        HashMap::from_iter(vec![("lastScraped".to_string(), "0".to_string())]),
-                    )
-                    .await?;
-
-                let redirect: Option<String> =
-                    redis.get(format!("redirect:{}", to)).await.unwrap_or(None);
+                new_pages.insert(to.clone());
+                ops.push(Op::SAdd("pages".to_string(), to.clone()));
+                ops.push(Op::HSet(
+                    format!("pages:data:{}", to),
+                    HashMap::from_iter(vec![("lastScraped".to_string(), "0".to_string())]),
+                ));
+
+                let redirect = db.get(&format!("redirect:{}", to)).await.unwrap_or(None);
                 if let Some(redirect) = redirect {
-                    redis.rpush("pages:queue", &[redirect]).await?;
+                    ops.push(queue::enqueue_op(db, &redirect, 0).await?);
+                } else if let Some(until) = politeness::disallowed_until(db, state, &to).await? {
+                    // robots.txt keeps us out, so leave it until that's worth checking again
+                    ops.push(recrawl::schedule_op(&to, until));
                 } else {
-                    redis.rpush("pages:queue", &[to.clone()]).await?;
+                    // The link from this result isn't stored yet, but still counts
+                    ops.push(queue::enqueue_op(db, &to, 1).await?);
                 }
             }
         }
     }
+    ops.extend(search::link_count_ops(state, &link_changes));
 
-    redis
-        .zincrby("scraper:leaderboard", 1.0, api_key_hash)
-        .await?;
+    ops.push(Op::ZIncrBy(
+        "scraper:leaderboard".to_string(),
+        1.0,
+        api_key_hash.to_string(),
+    ));
 
-    println!("Processed {}", work.result_url);
-    Ok(StatusCode::NO_CONTENT.into_response())
+    // Lets a retry of the same submission be recognised and skipped
+    if let Some(submission_id) = &work.submission_id {
+        ops.push(submission_op(api_key_hash, submission_id));
+    }
+
+    Ok((ops, link_changes))
+}
+
+#[derive(Deserialize, Debug)]
+struct SubmitQuery {
+    boost: Option<f64>,
 }
 
 async fn submit(
     AuthBearer(token): AuthBearer,
     State(state): State<AppState>,
+    Query(query): Query<SubmitQuery>,
     url: String,
 ) -> AppResult<Response<Body>> {
     if token != state.config.admin_key {
         return Ok(StatusCode::UNAUTHORIZED.into_response());
     }
 
-    let redis = state.redis.lock().await;
-    let transaction = redis.multi();
+    let db = &*state.db;
 
     let domain = get_domain(&url);
     if domain.is_none() {
@@ -402,152 +1014,30 @@ async fn submit(
     }
     let domain = state.base64.encode(domain.unwrap().as_bytes());
     let url = state.base64.encode(url.as_bytes());
+    let boost = query.boost.unwrap_or(queue::DEFAULT_SUBMIT_BOOST);
 
-    transaction.sadd("pages", &[url.clone()]).await?;
-    transaction
-        .pfadd(format!("domain:pages:{}", domain.clone()), &[url.clone()])
-        .await?;
-    transaction.rpush("pages:queue", &[url.clone()]).await?;
-    transaction
-        .hset(
+    db.apply(vec![
+        Op::SAdd("pages".to_string(), url.clone()),
+        Op::PfAdd(format!("domain:pages:{}", domain), url.clone()),
+        Op::HSet(
             format!("pages:data:{}", url),
-            HashMap::from_iter(vec![("lastScraped".to_string(), "0".to_string())]),
-        )
-        .await?;
-
-    transaction.exec(true).await?;
+            HashMap::from_iter(vec![
+                ("lastScraped".to_string(), "0".to_string()),
+                ("boost".to_string(), boost.to_string()),
+            ]),
+        ),
+    ])
+    .await?;
+    queue::enqueue(db, &url).await?;
     Ok(StatusCode::NO_CONTENT.into_response())
 }
 
-async fn graph(
-    AuthBearer(token): AuthBearer,
-    State(state): State<AppState>,
-) -> AppResult<Response<Body>> {
-    if token != state.config.admin_key {
-        return Ok(StatusCode::UNAUTHORIZED.into_response());
-    }
-
-    let mut graph = Graph {
-        links_to: HashMap::new(),
-        linked_from: HashMap::new(),
-        images: HashMap::new(),
-    };
-
-    let redis = state.redis.lock().await;
-    let pages = redis.smembers::<Vec<String>, _>("pages").await?;
-    for page_b64 in pages {
-        let redirect = redis
-            .get::<String, _>(format!("redirect:{}", page_b64))
-            .await
-            .ok();
-        if redirect.is_some() {
-            continue;
-        }
-
-        let page = String::from_utf8(state.base64.decode(&page_b64)?)?;
-
-        let page_domain = get_domain(&page);
-        if page_domain.is_none() {
-            continue;
-        }
-        let page_domain = page_domain.unwrap();
-
-        let links_to = redis
-            .smembers::<Vec<String>, _>(format!("pages:linksto:{}", page_b64))
-            .await
-            .unwrap_or_default();
-        for link_to in links_to {
-            let redirect = redis
-                .get::<String, _>(format!("redirect:{}", link_to))
-                .await
-                .ok();
-
-            let url = if let Some(redirect) = redirect {
-                String::from_utf8(state.base64.decode(&redirect)?)?
-            } else {
-                String::from_utf8(state.base64.decode(&link_to)?)?
-            };
-
-            if let Some(link_domain) = get_domain(&url) {
-                graph
-                    .links_to
-                    .entry(page_domain.clone())
-                    .or_default()
-                    .push(link_domain.clone());
-
-                graph.links_to.entry(link_domain.clone()).or_default();
-                graph.linked_from.entry(link_domain.clone()).or_default();
-                graph.images.entry(link_domain.clone()).or_default();
-
-                let image_hash = redis
-                    .hget::<String, _, String>(
-                        format!("link:{}:{}", page_b64, link_to),
-                        "imageHash".to_string(),
-                    )
-                    .await
-                    .ok();
-
-                if let Some(image_hash) = image_hash {
-                    let hashes = graph.images.entry(link_domain.clone()).or_default();
-                    if !hashes.contains(&image_hash) {
-                        hashes.push(image_hash.clone());
-                    }
-                }
-            }
-        }
-
-        let linked_from = redis
-            .smembers::<Vec<String>, _>(format!("pages:linkedfrom:{}", page_b64))
-            .await
-            .unwrap_or_default();
-        for link_from in linked_from {
-            let redirect = redis
-                .get::<String, _>(format!("redirect:{}", link_from))
-                .await
-                .ok();
-
-            let url = if let Some(redirect) = redirect {
-                String::from_utf8(state.base64.decode(&redirect)?)?
-            } else {
-                String::from_utf8(state.base64.decode(&link_from)?)?
-            };
-
-            if let Some(link_domain) = get_domain(&url) {
-                graph
-                    .linked_from
-                    .entry(page_domain.clone())
-                    .or_default()
-                    .push(link_domain.clone());
-
-                graph.links_to.entry(link_domain.clone()).or_default();
-                graph.linked_from.entry(link_domain.clone()).or_default();
-                graph.images.entry(link_domain.clone()).or_default();
-            }
-        }
-    }
-
-    // Deduplicate
-    for (_, links) in graph.links_to.iter_mut() {
-        links.sort();
-        links.dedup();
-    }
-    for (_, links) in graph.linked_from.iter_mut() {
-        links.sort();
-        links.dedup();
-    }
-    for (_, hashes) in graph.images.iter_mut() {
-        hashes.sort();
-        hashes.dedup();
-    }
-
-    Ok(Json(graph).into_response())
-}
-
 fn is_sha256(s: &str) -> bool {
     s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
 }
 
 async fn get_badge(Path(sha256): Path<String>) -> AppResult<Response<Body>> {
+    let sha256 = sha256.to_ascii_lowercase();
     if !is_sha256(&sha256) {
         return Ok(StatusCode::BAD_REQUEST.into_response());
     }
@@ -560,11 +1050,12 @@ async fn get_badge(Path(sha256): Path<String>) -> AppResult<Response<Body>> {
     }
 
     let data = tokio::fs::read(format!("./images/{}", sha256)).await?;
-    let mime = infer::get(&data)
-        .map(|x| x.mime_type())
-        .unwrap_or("application/octet-stream");
+    let Some(mime) = badge::content_type(&data) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
     let response = Response::builder()
-        .header("Content-Type", mime.to_string())
+        .header("Content-Type", mime)
+        .header("X-Content-Type-Options", "nosniff")
         .body(data.into())?;
     Ok(response)
 }
@@ -575,14 +1066,20 @@ async fn post_badge(
     Path(sha256): Path<String>,
     image: Bytes,
 ) -> AppResult<Response<Body>> {
-    if !auth_valid(state.redis.clone(), token.clone()).await? && token != state.config.admin_key {
+    if !keys::authorize(&state, &token, keys::Scope::Badge).await? {
         return Ok(StatusCode::UNAUTHORIZED.into_response());
     }
 
+    // Stored under the lowercase hash, so the same badge can't be uploaded twice
+    let sha256 = sha256.to_ascii_lowercase();
     if !is_sha256(&sha256) {
         return Ok(StatusCode::BAD_REQUEST.into_response());
     }
 
+    if image.len() > badge::MAX_BADGE_SIZE {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+    }
+
     tokio::fs::create_dir_all("./images").await?;
     let exists = tokio::fs::metadata(format!("./images/{}", sha256))
         .await
@@ -591,35 +1088,45 @@ async fn post_badge(
         return Ok(StatusCode::CONFLICT.into_response());
     }
 
+    // Charged before decoding, so invalid badges still cost what it took to check them
+    let api_key_hash = keys::hash(&state, &token);
+    if let Err(retry_after) = state.limiter.take_all(
+        &state,
+        &api_key_hash,
+        ratelimit::Kind::BadgeBytes,
+        image.len() as u64,
+    ) {
+        return Ok(ratelimit::too_many_requests(retry_after));
+    }
+
+    // Decoding is CPU-bound, so keep it off the runtime
+    let valid = {
+        let sha256 = sha256.clone();
+        let image = image.clone();
+        tokio::task::spawn_blocking(move || badge::validate(&sha256, &image)).await?
+    };
+    if let Err(e) = valid {
+        eprintln!("Rejected badge {}: {}", sha256, e);
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
     tokio::fs::write(format!("./images/{}", sha256), image).await?;
     Ok(StatusCode::NO_CONTENT.into_response())
 }
 
 async fn statistics(State(state): State<AppState>) -> AppResult<Json<Statistics>> {
-    let redis = state.redis.lock().await;
+    let db = &*state.db;
 
-    let queue: usize = redis.llen("pages:queue").await.unwrap_or(0);
-    let visited_pages: usize = redis.scard("pages:visited").await.unwrap_or(0);
-    let known_pages: usize = redis.scard("pages").await.unwrap_or(0);
+    let queue = db.zcard("pages:queue").await.unwrap_or(0);
+    let visited_pages = db.scard("pages:visited").await.unwrap_or(0);
+    let known_pages = db.scard("pages").await.unwrap_or(0);
 
-    let top_scrapers = redis
-        .zrange::<Vec<String>, _, _, _>("scraper:leaderboard", 0, 9, None, false, None, true)
-        .await?;
+    let top_scrapers = db.zrange_withscores("scraper:leaderboard", 0, 9).await?;
 
     let mut leaderboard = Vec::new();
-    for chunk in top_scrapers.chunks_exact(2) {
-        // ZRANGE WITHSCORES returns results as
-        // [item0, score0, item1, score1, item2, ...]
-
-        // Hash the API key so it's identifiable if you know the key,
-        // but otherwise anonymous
-        let mut hasher = Sha256::new();
-        hasher.update(&chunk[0]);
-        let api_key_hash = hasher.finalize();
-        let api_key_hash_b64 = state.base64.encode(api_key_hash);
-
-        let score = str::parse::<u64>(&chunk[1]).unwrap();
-        leaderboard.push((api_key_hash_b64, score))
+    for (api_key_hash, score) in top_scrapers {
+        let api_key_hash_b64 = anonymize_key(&state, &api_key_hash);
+        leaderboard.push((api_key_hash_b64, score as u64))
     }
 
     Ok(Json(Statistics {
@@ -630,135 +1137,326 @@ async fn statistics(State(state): State<AppState>) -> AppResult<Json<Statistics>
     }))
 }
 
-async fn update_queue_handler(
-    State(state): State<AppState>,
-    AuthBearer(token): AuthBearer,
-) -> AppResult<Response<Body>> {
-    if token != state.config.admin_key {
-        return Ok(StatusCode::UNAUTHORIZED.into_response());
-    }
-
-    update_queue(&state).await?;
-    Ok(StatusCode::NO_CONTENT.into_response())
-}
-
-async fn update_queue(state: &AppState) -> anyhow::Result<()> {
-    println!("Updating queue...");
-
-    let now = chrono::Utc::now().timestamp();
-    let week_ago = now - (60 * 60 * 24 * 7);
-
-    let redis = state.redis.lock().await;
-    redis.del("pages:queue").await?;
-
-    let max_pages: usize = redis
-        .get::<String, _>("domains:max_pages")
-        .await
-        .unwrap_or("100".to_string())
-        .parse()
-        .unwrap_or(100);
-
-    let pages = redis.smembers::<Vec<String>, _>("pages").await?;
-    for page in pages {
-        let last_scraped = redis
-            .hget::<String, _, String>(format!("pages:data:{}", page), "lastScraped".to_string())
-            .await
-            .unwrap_or("0".to_string())
-            .parse::<i64>()
-            .unwrap_or(0);
-
-        if last_scraped == 0 || last_scraped < week_ago {
-            // Safety check here just in case
-            if redis.sismember("domains:denylist", page.clone()).await? {
-                continue;
-            }
-
-            let pages = redis
-                .pfcount::<usize, _>(format!("domain:pages:{}", page.clone()))
-                .await?;
-            if pages >= max_pages {
-                continue;
-            }
-
-            let redirect: Option<String> = redis
-                .get(format!("redirect:{}", page))
-                .await
-                .unwrap_or(None);
-
-            if let Some(redirect) = redirect {
-                // Duplicate safety checks for the redirect URL
-                if redis
-                    .sismember("domains:denylist", redirect.clone())
-                    .await?
-                {
-                    continue;
-                }
-
-                let pages = redis
-                    .pfcount::<usize, _>(format!("domain:pages:{}", redirect.clone()))
-                    .await?;
-                if pages >= max_pages {
-                    continue;
-                }
-
-                redis.rpush("pages:queue", &[redirect]).await?;
-            } else {
-                redis.rpush("pages:queue", &[page.clone()]).await?;
-            }
-        }
-    }
-
-    let queue_size = redis.llen::<usize, _>("pages:queue").await?;
-    println!("Queue updated, new size: {}", queue_size);
-
-    Ok(())
-}
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let config_path = std::env::args().nth(1).unwrap_or("config.json".to_string());
     let config = std::fs::read_to_string(config_path)?;
     let config: Config = serde_json::from_str(&config)?;
 
-    let mut redis_config = fred::types::RedisConfig::default();
-    if let Some(host) = &config.redis_host {
-        let port = config.redis_port.unwrap_or(6379);
-        redis_config.server = ServerConfig::Centralized {
-            server: Server {
-                host: host.clone().into(),
-                port,
-            },
-        };
-    }
-
-    let client = RedisClient::new(redis_config, None, None, None);
-    client.connect();
-    client.wait_for_connect().await?;
-
     let app_state = AppState {
         config: config.clone(),
-        redis: Arc::new(Mutex::new(client)),
+        db: storage::open(&config).await?,
         base64: base64::prelude::BASE64_STANDARD,
+        graph: Arc::new(graph::GraphCache::default()),
+        limiter: Arc::new(ratelimit::RateLimiter::default()),
     };
 
-    update_queue(&app_state).await?;
+    keys::migrate(&app_state).await?;
+    recrawl::migrate(&app_state).await?;
+    domain::migrate(&app_state).await?;
+    search::migrate(&app_state).await?;
+    tokio::spawn(leases::reaper(app_state.clone()));
+    tokio::spawn(recrawl::scheduler(app_state.clone()));
+
+    graph::load(&app_state).await?;
+    tokio::spawn(graph::builder(app_state.clone()));
 
     let app = Router::new()
         .route("/create_account", post(create_account))
+        .route("/keys", get(keys::list))
+        .route("/keys/:id", get(keys::get))
+        .route("/keys/:id", patch(keys::update))
+        .route("/keys/:id", delete(keys::revoke))
         .route("/work", get(get_work))
         .route("/work", post(post_work))
+        .route("/work/batch", post(post_work_batch))
         .route("/submit", post(submit))
-        .route("/graph", get(graph))
+        .route("/graph", get(graph::graph))
+        .route("/graph/snapshots", get(graph::snapshots))
+        .route("/graph/diff", get(diff::graph_diff))
+        .route("/graph/pages", get(page_graph::page_graph))
         .route("/badge/:sha256", post(post_badge))
         .route("/badge/:sha256", get(get_badge))
+        .route("/domain/:name", get(domain::domain))
+        .route("/domain/:name", delete(purge::purge_handler))
+        .route("/search", get(search::search))
+        .route("/optout", post(optout::optout))
+        .route("/optout/verify", post(optout::verify))
         .route("/statistics", get(statistics))
-        .route("/update_queue", post(update_queue_handler))
+        .route("/update_queue", post(recrawl::reschedule_handler))
+        .route("/leases", get(leases::leases))
+        .route("/usage", get(ratelimit::usage))
+        .route("/verification", get(verify::verification))
+        .route("/denylist", get(denylist::list))
+        .route("/denylist", post(denylist::add))
+        .route("/denylist/:entry", delete(denylist::remove))
+        .route("/limits", get(limits::limits))
+        .route("/limits/max_pages", put(limits::set_max_pages))
+        .route("/limits/domain/:name", put(limits::set_override))
+        .route("/limits/domain/:name", delete(limits::remove_override))
         .with_state(app_state);
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port))
         .await
         .unwrap();
-    axum::serve(listener, app).await?;
+    // Opt-outs are limited per client address
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn state() -> AppState {
+        AppState::test(serde_json::json!({}))
+    }
+
+    fn work(links: &[&str]) -> WorkSchema {
+        WorkSchema {
+            orig_url: "https://a.com/".to_string(),
+            result_url: "https://a.com/".to_string(),
+            success: true,
+            links: Some(
+                links
+                    .iter()
+                    .map(|to| LinkSchema {
+                        to: to.to_string(),
+                        image: "https://a.com/88x31.png".to_string(),
+                        image_hash: "hash".to_string(),
+                    })
+                    .collect(),
+            ),
+            crawl_delay: None,
+            disallowed: None,
+            failure: None,
+            http_status: None,
+            submission_id: None,
+        }
+    }
+
+    /// Every key in the database along with its contents, to check nothing was written.
+    async fn dump(db: &dyn Storage) -> Vec<(String, String)> {
+        let mut keys = db.scan("*").await.unwrap();
+        keys.sort();
+
+        let mut dump = Vec::new();
+        for key in keys {
+            // Each read fails unless the key holds that type
+            let value = if let Ok(value) = db.get(&key).await {
+                format!("{:?}", value)
+            } else if let Ok(fields) = db.hgetall(&key).await {
+                format!("{:?}", fields.into_iter().collect::<BTreeMap<_, _>>())
+            } else if let Ok(members) = db.smembers(&key).await {
+                format!("{:?}", members.into_iter().collect::<BTreeSet<_>>())
+            } else if let Ok(members) = db.zrange_withscores(&key, 0, -1).await {
+                format!("{:?}", members)
+            } else if let Ok(values) = db.lrange(&key, 0, -1).await {
+                format!("{:?}", values)
+            } else {
+                format!("{:?}", db.pfcount(&key).await.unwrap())
+            };
+            dump.push((key, value));
+        }
+        dump
+    }
+
+    #[tokio::test]
+    async fn results_for_denylisted_domains_are_rejected() {
+        let state = state();
+        let db = &*state.db;
+        let page = state.base64.encode("https://a.com/".as_bytes());
+        db.sadd("pages", &page).await.unwrap();
+        commit_work(
+            &state,
+            db,
+            "key",
+            work(&["https://b.com/"]),
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // Opting out denylists the domain and then purges it
+        db.sadd("domains:denylist", &state.base64.encode("a.com".as_bytes()))
+            .await
+            .unwrap();
+        purge::purge(&state, "a.com").await.unwrap();
+        let before = dump(db).await;
+
+        // A scraper that claimed the page before the opt-out reports back afterwards
+        let status = process_work(&state, db, "key", work(&["https://c.com/"]))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(dump(db).await, before);
+    }
+
+    #[tokio::test]
+    async fn commit_work_round_trip() {
+        let state = state();
+        let db = &*state.db;
+        let encode = |url: &str| state.base64.encode(url.as_bytes());
+        let page = encode("https://a.com/");
+        let link = encode("https://b.com/");
+        db.sadd("pages", &page).await.unwrap();
+
+        commit_work(
+            &state,
+            db,
+            "key",
+            work(&["https://b.com/"]),
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert!(db.sismember("pages", &link).await.unwrap());
+        assert!(db.zscore("pages:queue", &link).await.unwrap().is_some());
+        assert_eq!(
+            db.smembers(&format!("pages:linksto:{}", page))
+                .await
+                .unwrap(),
+            vec![link.clone()]
+        );
+        assert_eq!(
+            db.smembers(&format!("pages:linkedfrom:{}", link))
+                .await
+                .unwrap(),
+            vec![page.clone()]
+        );
+        let seen = history::seen(db, &page, &link).await.unwrap();
+        assert!(seen.visible(None));
+        assert_eq!(
+            db.zscore("scraper:leaderboard", "key").await.unwrap(),
+            Some(1.0)
+        );
+        assert_eq!(
+            db.zscore("search:inbound", "b.com").await.unwrap(),
+            Some(1.0)
+        );
+
+        let graph = graph::build(&state, None).await.unwrap();
+        assert_eq!(graph.links_to["a.com"], vec!["b.com"]);
+        assert_eq!(graph.linked_from["b.com"], vec!["a.com"]);
+
+        // Dropping the link keeps it around as history, but takes it out of the graph
+        commit_work(&state, db, "key", work(&[]), Vec::new(), None)
+            .await
+            .unwrap();
+
+        assert!(!history::seen(db, &page, &link).await.unwrap().visible(None));
+        assert_eq!(
+            db.zscore("search:inbound", "b.com").await.unwrap(),
+            Some(0.0)
+        );
+        let graph = graph::build(&state, None).await.unwrap();
+        assert!(graph.links_to.get("a.com").is_none_or(Vec::is_empty));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_results_keep_link_counts() {
+        let state = state();
+        let pages = (0..32)
+            .map(|i| format!("https://a.com/{}", i))
+            .collect::<Vec<_>>();
+        for page in &pages {
+            state
+                .db
+                .sadd("pages", &state.base64.encode(page.as_bytes()))
+                .await
+                .unwrap();
+        }
+
+        // Every page on a.com starts linking to b.com at once, then stops at once
+        for links in [vec!["https://b.com/"], vec![]] {
+            let tasks = pages.iter().map(|page| {
+                let state = state.clone();
+                let work = WorkSchema {
+                    orig_url: page.clone(),
+                    result_url: page.clone(),
+                    ..work(&links)
+                };
+                tokio::spawn(async move {
+                    commit_work(&state, &*state.db, "key", work, Vec::new(), None).await
+                })
+            });
+            for task in futures::future::join_all(tasks).await {
+                task.unwrap().unwrap();
+            }
+
+            let db = &*state.db;
+            let b = state.base64.encode("b.com".as_bytes());
+            let count = db
+                .hget(&format!("domain:linkcounts:{}", b), "a.com")
+                .await
+                .unwrap();
+            let linked = db
+                .sismember(&format!("domain:linkedfrom:{}", b), "a.com")
+                .await
+                .unwrap();
+            let inbound = db.zscore("search:inbound", "b.com").await.unwrap();
+            if links.is_empty() {
+                assert_eq!(count, None);
+                assert!(!linked);
+                assert_eq!(inbound, Some(0.0));
+            } else {
+                assert_eq!(count, Some(pages.len().to_string()));
+                assert!(linked);
+                assert_eq!(inbound, Some(1.0));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn only_a_leased_verifier_settles_held_results() {
+        let state = AppState::test(serde_json::json!({
+            "verification": { "sample_rate": 1.0, "trust_threshold": 1000 },
+        }));
+        let db = &*state.db;
+        let page = state.base64.encode("https://a.com/".as_bytes());
+        db.sadd("pages", &page).await.unwrap();
+        let pending = format!("verify:pending:{}", page);
+
+        let status = process_work(&state, db, "submitter", work(&["https://b.com/"]))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(db.exists(&pending).await.unwrap());
+
+        // Never handed the page, so it can't vouch for it
+        let status = process_work(&state, db, "other", work(&["https://b.com/"]))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(db.exists(&pending).await.unwrap());
+
+        let claimed = verify::claim(&state, db, "verifier").await.unwrap();
+        assert_eq!(claimed.as_deref(), Some("https://a.com/"));
+        let status = process_work(&state, db, "verifier", work(&["https://b.com/"]))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        assert!(!db.exists(&pending).await.unwrap());
+        assert!(!leases::holds(db, "verifier", &page).await.unwrap());
+        for key in ["submitter", "verifier"] {
+            assert_eq!(db.zscore("verify:trust", key).await.unwrap(), Some(1.0));
+            assert_eq!(
+                db.zscore("scraper:leaderboard", key).await.unwrap(),
+                Some(1.0)
+            );
+        }
+        assert_eq!(db.zscore("verify:trust", "other").await.unwrap(), None);
+        let link = state.base64.encode("https://b.com/".as_bytes());
+        assert!(history::seen(db, &page, &link).await.unwrap().visible(None));
+    }
+}
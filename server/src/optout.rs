@@ -0,0 +1,264 @@
+use crate::{get_domain, purge, ratelimit, storage::Expiry, AppResult, AppState};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use base64::Engine;
+use reqwest::redirect;
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use url::Host;
+use uuid::Uuid;
+
+/// How long a site owner has to publish their token, in seconds
+const OPTOUT_TOKEN_TTL: i64 = 60 * 60 * 24;
+
+/// Where the token can be published on the site
+const WELL_KNOWN_PATH: &str = "/.well-known/eightyeightthirtyone.txt";
+
+/// The name of the meta tag the token can be published in instead
+const META_NAME: &str = "eightyeightthirtyone-verification";
+
+const USER_AGENT: &str =
+    "eightyeightthirtyone/1.0.0 (https://github.com/NotNite/eightyeightthirtyone)";
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OptOutChallenge {
+    pub domain: String,
+    pub token: String,
+    pub well_known_url: String,
+    pub meta_tag: String,
+    pub expires: i64,
+}
+
+/// Turns whatever the owner typed into a bare domain, or `None` if it isn't one.
+fn normalize_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('/').to_lowercase();
+    let domain = domain
+        .strip_prefix("https://")
+        .or_else(|| domain.strip_prefix("http://"))
+        .unwrap_or(&domain);
+
+    // IP addresses don't have a domain, so they're rejected here too
+    get_domain(&format!("https://{}/", domain)).filter(|x| x == domain && x.contains('.'))
+}
+
+/// Whether `ip` is somewhere on the Internet, rather than on our own network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || a == 0
+                || a >= 240
+                // Carrier-grade NAT
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local
+                    || (first & 0xfe00) == 0xfc00
+                    // Link-local
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Looks up `domain`, failing if any of its addresses aren't public.
+async fn lookup_public(domain: &str) -> anyhow::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((domain, 0)).await?.collect();
+    if addrs.is_empty() {
+        anyhow::bail!("{} has no addresses", domain);
+    }
+    if let Some(addr) = addrs.iter().find(|x| !is_public(x.ip())) {
+        anyhow::bail!("{} resolves to non-public address {}", domain, addr.ip());
+    }
+
+    Ok(addrs)
+}
+
+/// Builds a client that only connects to the given hosts, at the addresses they
+/// were already checked to have, and won't be redirected anywhere else.
+///
+/// Pinning the addresses stops the name from resolving somewhere private by the
+/// time the request is made.
+fn pinned_client(hosts: &[(String, Vec<SocketAddr>)]) -> anyhow::Result<reqwest::Client> {
+    let allowed: HashSet<String> = hosts.iter().map(|(host, _)| host.clone()).collect();
+    let policy = redirect::Policy::custom(move |attempt| {
+        let ok = attempt.previous().len() < 5
+            && matches!(attempt.url().host(), Some(Host::Domain(host)) if allowed.contains(host));
+        if ok {
+            attempt.follow()
+        } else {
+            attempt.stop()
+        }
+    });
+
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(10))
+        .user_agent(USER_AGENT)
+        .no_proxy()
+        .redirect(policy);
+    for (host, addrs) in hosts {
+        builder = builder.resolve_to_addrs(host, addrs);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Checks whether the token has been published on the domain, either as the
+/// well-known file or as a meta tag on the front page.
+async fn token_published(domain: &str, token: &str) -> anyhow::Result<bool> {
+    let Ok(addrs) = lookup_public(domain).await else {
+        return Ok(false);
+    };
+    let mut hosts = vec![(domain.to_string(), addrs)];
+    let well_known_client = pinned_client(&hosts)?;
+
+    // Front pages often redirect to the `www.` subdomain, so that's allowed too
+    let www = format!("www.{}", domain);
+    if let Ok(addrs) = lookup_public(&www).await {
+        hosts.push((www, addrs));
+    }
+    let client = pinned_client(&hosts)?;
+
+    for scheme in ["https", "http"] {
+        let response = well_known_client
+            .get(format!("{}://{}{}", scheme, domain, WELL_KNOWN_PATH))
+            .send()
+            .await;
+        if let Ok(response) = response {
+            if response.status().is_success()
+                && response.text().await.unwrap_or_default().trim() == token
+            {
+                return Ok(true);
+            }
+        }
+
+        let response = client.get(format!("{}://{}/", scheme, domain)).send().await;
+        if let Ok(response) = response {
+            if !response.status().is_success() {
+                continue;
+            }
+
+            let html = scraper::Html::parse_document(&response.text().await.unwrap_or_default());
+            let selector = scraper::Selector::parse(&format!(r#"meta[name="{}"]"#, META_NAME))
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            let found = html
+                .select(&selector)
+                .any(|x| x.value().attr("content").map(|x| x.trim()) == Some(token));
+            if found {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Both endpoints can be hit by anyone, and make the server fetch from the
+/// Internet, so they share an allowance per client address.
+fn limit(state: &AppState, addr: SocketAddr) -> Result<(), Duration> {
+    let id = format!("ip:{}", addr.ip());
+    state
+        .limiter
+        .take_all(state, &id, ratelimit::Kind::OptOuts, 1)
+}
+
+/// Starts an opt-out, handing back a token for the owner to publish on their site.
+///
+/// Asking again before the token expires returns the same token.
+pub async fn optout(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    domain: String,
+) -> AppResult<Response<Body>> {
+    if let Err(wait) = limit(&state, addr) {
+        return Ok(ratelimit::too_many_requests(wait));
+    }
+
+    let Some(domain) = normalize_domain(&domain) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    // There's no way to verify a domain we'd refuse to fetch, so don't hand out a token for it
+    if lookup_public(&domain).await.is_err() {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let key = format!("optout:{}", state.base64.encode(domain.as_bytes()));
+    let db = &*state.db;
+    let token = match db.get(&key).await? {
+        Some(token) => token,
+        None => {
+            let token = Uuid::new_v4().to_string();
+            db.set(&key, &token, Some(Expiry::Seconds(OPTOUT_TOKEN_TTL)))
+                .await?;
+            token
+        }
+    };
+    let ttl = db.ttl(&key).await?;
+
+    Ok(Json(OptOutChallenge {
+        well_known_url: format!("https://{}{}", domain, WELL_KNOWN_PATH),
+        meta_tag: format!(r#"<meta name="{}" content="{}">"#, META_NAME, token),
+        expires: chrono::Utc::now().timestamp() + ttl.max(0),
+        domain,
+        token,
+    })
+    .into_response())
+}
+
+/// Finishes an opt-out once the token is live on the site, deleting everything
+/// stored about the domain and denylisting it.
+pub async fn verify(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    domain: String,
+) -> AppResult<Response<Body>> {
+    if let Err(wait) = limit(&state, addr) {
+        return Ok(ratelimit::too_many_requests(wait));
+    }
+
+    let Some(domain) = normalize_domain(&domain) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let domain_b64 = state.base64.encode(domain.as_bytes());
+    let key = format!("optout:{}", domain_b64);
+    let db = &*state.db;
+    let token = db.get(&key).await?;
+    let Some(token) = token else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    if !token_published(&domain, &token).await? {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    // Denylist first so nothing gets recorded again while the purge runs
+    db.sadd("domains:denylist", &domain_b64).await?;
+    db.del(&key).await?;
+    purge::purge(&state, &domain).await?;
+
+    println!("{} opted out", domain);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
@@ -0,0 +1,214 @@
+use crate::{
+    get_domain, graph, history, keys, resolve_page, storage::Storage, AppResult, AppState,
+};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PageGraph {
+    pub nodes: Vec<PageNode>,
+    pub links: Vec<PageLink>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PageNode {
+    pub url: String,
+    pub domain: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PageLink {
+    pub from: String,
+    pub to: String,
+    pub image_url: Option<String>,
+    pub image_hash: Option<String>,
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>,
+    /// When a scrape of the page stopped finding the link
+    pub removed: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PageGraphQuery {
+    domain: Option<String>,
+    /// Unix timestamp to show the links as they were at
+    as_of: Option<i64>,
+}
+
+/// Reads a link, if it was there at `as_of` (or is there now).
+async fn link(
+    db: &dyn Storage,
+    state: &AppState,
+    from_b64: &str,
+    to_b64: &str,
+    from: &str,
+    as_of: Option<i64>,
+) -> anyhow::Result<Option<PageLink>> {
+    let data = db
+        .hgetall(&format!("link:{}:{}", from_b64, to_b64))
+        .await
+        .unwrap_or_default();
+    let seen = history::Seen::from_fields(&data);
+    if !seen.visible(as_of) {
+        return Ok(None);
+    }
+
+    let image_url = data
+        .get("imageUrl")
+        .and_then(|x| state.base64.decode(x).ok())
+        .and_then(|x| String::from_utf8(x).ok());
+
+    Ok(Some(PageLink {
+        from: from.to_string(),
+        to: resolve_page(db, state, to_b64).await?,
+        image_url,
+        image_hash: data.get("imageHash").cloned(),
+        first_seen: seen.first_seen,
+        last_seen: seen.last_seen,
+        removed: seen.removed,
+    }))
+}
+
+/// Collects the links out of a page, and into it when `inbound` is set.
+///
+/// Inbound links from pages in `selected` are left out, as they're picked up
+/// when those pages are visited.
+async fn page_links(
+    db: &dyn Storage,
+    state: &AppState,
+    selected: &HashMap<String, String>,
+    inbound: bool,
+    page_b64: &str,
+    page: &str,
+    as_of: Option<i64>,
+) -> anyhow::Result<Vec<PageLink>> {
+    let redirect = db
+        .get(&format!("redirect:{}", page_b64))
+        .await
+        .unwrap_or(None);
+    if redirect.is_some() {
+        return Ok(Vec::new());
+    }
+
+    let links_to = db
+        .smembers(&format!("pages:linksto:{}", page_b64))
+        .await
+        .unwrap_or_default();
+    let mut links = futures::future::try_join_all(
+        links_to
+            .iter()
+            .map(|link_to| link(db, state, page_b64, link_to, page, as_of)),
+    )
+    .await?
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    if !inbound {
+        return Ok(links);
+    }
+
+    let linked_from = db
+        .smembers(&format!("pages:linkedfrom:{}", page_b64))
+        .await
+        .unwrap_or_default();
+    let inbound = futures::future::try_join_all(
+        linked_from
+            .iter()
+            .filter(|link_from| !selected.contains_key(*link_from))
+            .map(|link_from| async move {
+                let from = resolve_page(db, state, link_from).await?;
+                link(db, state, link_from, page_b64, &from, as_of).await
+            }),
+    )
+    .await?;
+    links.extend(inbound.into_iter().flatten());
+
+    Ok(links)
+}
+
+/// Builds a graph of individual pages, optionally only around a single domain.
+///
+/// When filtering, both the links out of the domain's pages and the links
+/// into them are included. With `as_of`, only links that were around at that
+/// time are, and otherwise only ones that haven't been removed.
+pub async fn build(
+    state: &AppState,
+    domain: Option<&str>,
+    as_of: Option<i64>,
+) -> anyhow::Result<PageGraph> {
+    let db = &*state.db;
+    let pages = db.smembers("pages").await?;
+
+    // Decoding is cheap, so filter by domain locally instead of asking the database
+    let mut selected = HashMap::new();
+    for page_b64 in pages {
+        let page = String::from_utf8(state.base64.decode(&page_b64)?)?;
+        if domain.is_none() || get_domain(&page).as_deref() == domain {
+            selected.insert(page_b64, page);
+        }
+    }
+
+    let mut links = Vec::new();
+    let entries = selected.iter().collect::<Vec<_>>();
+    for chunk in entries.chunks(graph::BUILD_BATCH) {
+        let fetched = futures::future::try_join_all(chunk.iter().map(|(page_b64, page)| {
+            page_links(
+                db,
+                state,
+                &selected,
+                domain.is_some(),
+                page_b64,
+                page,
+                as_of,
+            )
+        }))
+        .await?;
+        links.extend(fetched.into_iter().flatten());
+    }
+
+    let mut nodes = selected.into_values().collect::<BTreeSet<_>>();
+    for link in &links {
+        nodes.insert(link.from.clone());
+        nodes.insert(link.to.clone());
+    }
+
+    let mut seen = HashSet::new();
+    links.retain(|link| seen.insert((link.from.clone(), link.to.clone())));
+
+    Ok(PageGraph {
+        nodes: nodes
+            .into_iter()
+            .map(|url| PageNode {
+                domain: get_domain(&url),
+                url,
+            })
+            .collect(),
+        links,
+    })
+}
+
+pub async fn page_graph(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Query(query): Query<PageGraphQuery>,
+) -> AppResult<Response<Body>> {
+    if !keys::authorize(&state, &token, keys::Scope::Analytics).await? {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let graph = build(&state, query.domain.as_deref(), query.as_of).await?;
+    Ok(Json(graph).into_response())
+}
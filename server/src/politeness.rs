@@ -0,0 +1,195 @@
+use crate::{
+    get_domain, recrawl,
+    storage::{Expiry, Op, Storage},
+    AppState,
+};
+use base64::Engine;
+use std::time::Duration;
+
+/// Minimum time between handing out pages on the same domain, in seconds
+pub const DEFAULT_DOMAIN_INTERVAL: u64 = 5;
+
+/// How long what a scraper found in a robots.txt is honored before one has to report it again
+const ROBOTS_TTL: i64 = 60 * 60 * 24;
+
+/// Upper bound on Crawl-delay so a silly robots.txt can't park a domain forever
+const MAX_CRAWL_DELAY: f64 = 60.0 * 60.0;
+
+/// Remembers the Crawl-delay a scraper found in a domain's robots.txt.
+///
+/// Returns nothing to write if the delay is nonsense.
+pub fn crawl_delay_op(state: &AppState, domain: &str, delay: f64) -> Option<Op> {
+    if !delay.is_finite() || delay <= 0.0 {
+        return None;
+    }
+
+    let domain = state.base64.encode(domain.as_bytes());
+    Some(Op::Set(
+        format!("domain:crawldelay:{}", domain),
+        delay.min(MAX_CRAWL_DELAY).to_string(),
+        Some(Expiry::Seconds(ROBOTS_TTL)),
+    ))
+}
+
+/// Remembers that a domain's robots.txt doesn't let us scrape `page`.
+///
+/// Disallowed pages are kept per domain in `domain:disallowed:*`, scored by when
+/// they were reported. The page leaves the queue, and comes back up for a recrawl
+/// once the report expires, in case the site has changed its mind.
+pub fn disallow_ops(state: &AppState, page: &str, domain: &str, now: i64) -> Vec<Op> {
+    let domain = state.base64.encode(domain.as_bytes());
+    vec![
+        Op::ZAdd(
+            format!("domain:disallowed:{}", domain),
+            now as f64,
+            page.to_string(),
+        ),
+        Op::ZRem("pages:queue".to_string(), page.to_string()),
+        recrawl::schedule_op(page, now + ROBOTS_TTL),
+    ]
+}
+
+/// Clears a disallow once a scraper has been let in to `page`.
+pub fn allow_op(state: &AppState, page: &str, domain: &str) -> Op {
+    let domain = state.base64.encode(domain.as_bytes());
+    Op::ZRem(format!("domain:disallowed:{}", domain), page.to_string())
+}
+
+/// When the disallow on `page` runs out, if it's still disallowed.
+pub async fn disallowed_until(
+    db: &dyn Storage,
+    state: &AppState,
+    page: &str,
+) -> anyhow::Result<Option<i64>> {
+    let url = String::from_utf8(state.base64.decode(page)?)?;
+    let Some(domain) = get_domain(&url) else {
+        return Ok(None);
+    };
+
+    let domain = state.base64.encode(domain.as_bytes());
+    let reported = db
+        .zscore(&format!("domain:disallowed:{}", domain), page)
+        .await?;
+    let now = chrono::Utc::now().timestamp();
+    Ok(reported
+        .map(|x| x as i64 + ROBOTS_TTL)
+        .filter(|until| *until > now))
+}
+
+/// A page robots.txt keeps us out of, as recorded in `domain:disallowed:*`.
+pub struct Disallow {
+    pub page: String,
+    pub reported: i64,
+    pub expires: i64,
+}
+
+/// The pages on `domain` that robots.txt currently keeps us out of.
+pub async fn disallowed(
+    db: &dyn Storage,
+    state: &AppState,
+    domain: &str,
+) -> anyhow::Result<Vec<Disallow>> {
+    let domain = state.base64.encode(domain.as_bytes());
+    let now = chrono::Utc::now().timestamp();
+    let pages = db
+        .zrange_withscores(&format!("domain:disallowed:{}", domain), 0, -1)
+        .await?;
+    Ok(pages
+        .into_iter()
+        .map(|(page, reported)| Disallow {
+            page,
+            reported: reported as i64,
+            expires: reported as i64 + ROBOTS_TTL,
+        })
+        .filter(|x| x.expires > now)
+        .collect())
+}
+
+/// How long to wait between handouts for a domain, in milliseconds.
+///
+/// This is the configured interval (or its per-domain override), raised to the
+/// domain's Crawl-delay if one has been reported.
+async fn interval(db: &dyn Storage, state: &AppState, domain: &str) -> anyhow::Result<i64> {
+    let configured = state
+        .config
+        .domain_intervals
+        .as_ref()
+        .and_then(|overrides| overrides.get(domain))
+        .copied()
+        .or(state.config.domain_interval)
+        .unwrap_or(DEFAULT_DOMAIN_INTERVAL) as f64;
+
+    let domain = state.base64.encode(domain.as_bytes());
+    let crawl_delay = db
+        .get(&format!("domain:crawldelay:{}", domain))
+        .await?
+        .and_then(|delay| delay.parse::<f64>().ok())
+        .unwrap_or(0.0);
+
+    Ok((configured.max(crawl_delay) * 1000.0) as i64)
+}
+
+/// How long until a page on `domain` can be handed out again, if one was handed out too recently.
+pub async fn cooldown(
+    db: &dyn Storage,
+    state: &AppState,
+    domain: &str,
+) -> anyhow::Result<Option<Duration>> {
+    let domain = state.base64.encode(domain.as_bytes());
+    let key = format!("domain:cooldown:{}", domain);
+    Ok(match db.ttl(&key).await? {
+        // No cooldown key
+        -2 => None,
+        // Cooldowns always expire, so one that doesn't would hold the domain forever
+        -1 => {
+            db.del(&key).await?;
+            None
+        }
+        ttl => Some(Duration::from_secs(ttl.max(1) as u64)),
+    })
+}
+
+/// Claims the next handout slot for a domain.
+///
+/// Returns false if a page on the domain was handed out too recently. Only call
+/// this once the page is definitely being handed out, as the cooldown starts here.
+pub async fn try_acquire(db: &dyn Storage, state: &AppState, domain: &str) -> anyhow::Result<bool> {
+    let interval = interval(db, state, domain).await?;
+    if interval <= 0 {
+        return Ok(true);
+    }
+
+    // SET NX only succeeds once the previous cooldown key has expired
+    let domain = state.base64.encode(domain.as_bytes());
+    db.set_nx(
+        &format!("domain:cooldown:{}", domain),
+        "1",
+        Expiry::Millis(interval),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cooldowns_without_expiry_are_dropped() {
+        let state = AppState::test(serde_json::json!({}));
+        let db = &*state.db;
+        let key = format!(
+            "domain:cooldown:{}",
+            state.base64.encode("a.com".as_bytes())
+        );
+
+        assert_eq!(cooldown(db, &state, "a.com").await.unwrap(), None);
+
+        db.set(&key, "1", Some(Expiry::Seconds(30))).await.unwrap();
+        let wait = cooldown(db, &state, "a.com").await.unwrap().unwrap();
+        assert!(wait > Duration::from_secs(20));
+
+        db.set(&key, "1", None).await.unwrap();
+        assert_eq!(cooldown(db, &state, "a.com").await.unwrap(), None);
+        assert!(!db.exists(&key).await.unwrap());
+    }
+}
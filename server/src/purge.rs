@@ -0,0 +1,223 @@
+use crate::{get_domain, graph, leases, recrawl, storage::Storage, verify, AppResult, AppState};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use base64::Engine;
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// What a purge removed.
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PurgeReport {
+    pub domain: String,
+    /// Pages removed from `pages`
+    pub pages: usize,
+    pub visited: usize,
+    pub failed: usize,
+    /// Pages taken off the queue
+    pub queued: usize,
+    /// `link:*:*` records deleted
+    pub links: usize,
+    /// Edges removed from the link sets of the pages on either end
+    pub reverse_edges: usize,
+    /// `redirect:*` records deleted
+    pub redirects: usize,
+    /// Any other keys deleted outright
+    pub keys: usize,
+}
+
+/// Removes a single page, along with every link into or out of it.
+///
+/// Returns the domains the page linked to, so their search entries can be updated.
+async fn purge_page(
+    db: &dyn Storage,
+    state: &AppState,
+    report: &mut PurgeReport,
+    page: &str,
+) -> anyhow::Result<HashSet<String>> {
+    let mut linked_domains = HashSet::new();
+
+    let links_to = db
+        .smembers(&format!("pages:linksto:{}", page))
+        .await
+        .unwrap_or_default();
+    for link_to in links_to {
+        report.reverse_edges += db
+            .srem(&format!("pages:linkedfrom:{}", link_to), page)
+            .await? as usize;
+        report.links += db.del(&format!("link:{}:{}", page, link_to)).await? as usize;
+
+        let url = String::from_utf8(state.base64.decode(&link_to)?)?;
+        linked_domains.extend(get_domain(&url));
+    }
+
+    let linked_from = db
+        .smembers(&format!("pages:linkedfrom:{}", page))
+        .await
+        .unwrap_or_default();
+    for link_from in linked_from {
+        report.reverse_edges += db
+            .srem(&format!("pages:linksto:{}", link_from), page)
+            .await? as usize;
+        report.links += db.del(&format!("link:{}:{}", link_from, page)).await? as usize;
+    }
+
+    for key in [
+        format!("pages:linksto:{}", page),
+        format!("pages:linkedfrom:{}", page),
+        format!("pages:data:{}", page),
+        format!("verify:pending:{}", page),
+    ] {
+        report.keys += db.del(&key).await? as usize;
+    }
+
+    report.pages += db.srem("pages", page).await? as usize;
+    report.visited += db.srem("pages:visited", page).await? as usize;
+    report.failed += db.srem("pages:failed", page).await? as usize;
+    db.srem("pages:dead", page).await?;
+    report.queued += db.zrem("pages:queue", page).await? as usize;
+    db.zrem("verify:queue", page).await?;
+    recrawl::unschedule(db, page).await?;
+    leases::revoke(db, page).await?;
+
+    Ok(linked_domains)
+}
+
+/// Sweeps up `link:*:*` records that no link set points at any more, like the
+/// ones left under a page's old URL after it redirected.
+async fn purge_stray_links(
+    db: &dyn Storage,
+    state: &AppState,
+    report: &mut PurgeReport,
+    domain: &str,
+) -> anyhow::Result<()> {
+    for key in db.scan("link:*").await? {
+        // Base64 never contains a colon, so this splits cleanly
+        let Some((from, to)) = key.strip_prefix("link:").and_then(|x| x.split_once(':')) else {
+            continue;
+        };
+
+        let matches = [from, to].into_iter().any(|x| {
+            state
+                .base64
+                .decode(x)
+                .ok()
+                .and_then(|x| String::from_utf8(x).ok())
+                .and_then(|x| get_domain(&x))
+                .as_deref()
+                == Some(domain)
+        });
+        if matches {
+            report.links += db.del(&key).await? as usize;
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes everything stored about a domain: its pages, the links to and from
+/// them (and the badges recorded on those links), its redirects and its search entry.
+///
+/// This doesn't stop the domain from being discovered again - denylist it for that.
+pub async fn purge(state: &AppState, domain: &str) -> anyhow::Result<PurgeReport> {
+    let domain_b64 = state.base64.encode(domain.as_bytes());
+    let mut report = PurgeReport {
+        domain: domain.to_string(),
+        ..Default::default()
+    };
+
+    let db = &*state.db;
+
+    let pages = db.smembers("pages").await?;
+
+    let mut linked_domains = HashSet::new();
+    for page in pages {
+        let url = String::from_utf8(state.base64.decode(&page)?)?;
+        if get_domain(&url).as_deref() != Some(domain) {
+            continue;
+        }
+
+        linked_domains.extend(purge_page(db, state, &mut report, &page).await?);
+    }
+
+    // Redirects are indexed under both ends, so clear the other end's index too
+    let redirects = db
+        .smembers(&format!("domain:redirects:{}", domain_b64))
+        .await?;
+    for from in redirects {
+        let to = db.get(&format!("redirect:{}", from)).await?;
+        report.redirects += db.del(&format!("redirect:{}", from)).await? as usize;
+
+        let mut ends = vec![from];
+        ends.extend(to);
+        for end in ends {
+            let url = String::from_utf8(state.base64.decode(&end)?)?;
+            let Some(end_domain) = get_domain(&url).filter(|x| x != domain) else {
+                // Pages that redirected away aren't in `pages` any more, so tidy up after them here
+                purge_page(db, state, &mut report, &end).await?;
+                continue;
+            };
+
+            let end_domain = state.base64.encode(end_domain.as_bytes());
+            db.srem(&format!("domain:redirects:{}", end_domain), &end)
+                .await?;
+        }
+    }
+
+    purge_stray_links(db, state, &mut report, domain).await?;
+
+    // Pages out with scrapers or waiting on verification could otherwise be written back later
+    leases::revoke_where(db, state, |x| x == domain).await?;
+    verify::drop_where(db, |x| x == domain).await?;
+
+    for linked_domain in linked_domains.into_iter().filter(|x| x != domain) {
+        let linked_domain_b64 = state.base64.encode(linked_domain.as_bytes());
+        db.hdel(&format!("domain:linkcounts:{}", linked_domain_b64), domain)
+            .await?;
+        let removed = db
+            .srem(&format!("domain:linkedfrom:{}", linked_domain_b64), domain)
+            .await?;
+        if removed {
+            db.zincrby("search:inbound", -1.0, &linked_domain).await?;
+        }
+    }
+
+    db.zrem("search:domains", domain).await?;
+    db.zrem("search:inbound", domain).await?;
+    for key in [
+        format!("domain:pages:{}", domain_b64),
+        format!("domain:redirects:{}", domain_b64),
+        format!("domain:linkedfrom:{}", domain_b64),
+        format!("domain:linkcounts:{}", domain_b64),
+        format!("domain:crawldelay:{}", domain_b64),
+        format!("domain:cooldown:{}", domain_b64),
+        format!("domain:failures:{}", domain_b64),
+        format!("domain:disallowed:{}", domain_b64),
+    ] {
+        report.keys += db.del(&key).await? as usize;
+    }
+
+    graph::forget(domain).await?;
+    state.graph.invalidate();
+    println!("Purged {}: {:?}", domain, report);
+    Ok(report)
+}
+
+pub async fn purge_handler(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+    Path(domain): Path<String>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let report = purge(&state, &domain.to_lowercase()).await?;
+    Ok(Json(report).into_response())
+}
@@ -0,0 +1,146 @@
+use crate::storage::{Op, Storage};
+
+/// Boost given to pages submitted through `/submit` when none is specified
+pub const DEFAULT_SUBMIT_BOOST: f64 = 1000.0;
+
+/// Staleness given to pages that have never been scraped, in days.
+///
+/// This puts them ahead of anything that's merely old.
+const NEVER_SCRAPED_STALENESS: f64 = 730.0;
+
+/// Cap on how much staleness counts towards priority, in days
+const MAX_STALENESS: f64 = 365.0;
+
+/// Weight of the (log-scaled) number of pages linking to a page
+const INBOUND_WEIGHT: f64 = 50.0;
+
+/// Works out where a page belongs in the queue - higher scores are handed out first.
+///
+/// Priority comes from how long it's been since the page was scraped (with
+/// never-scraped pages first), how many pages link to it, and any manual boost.
+/// `new_inbound` counts links that are about to be written but aren't stored yet.
+pub async fn priority(db: &dyn Storage, page: &str, new_inbound: usize) -> anyhow::Result<f64> {
+    let now = chrono::Utc::now().timestamp();
+
+    let data = db
+        .hmget(&format!("pages:data:{}", page), &["lastScraped", "boost"])
+        .await?;
+    let last_scraped = data
+        .first()
+        .cloned()
+        .flatten()
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or(0);
+    let boost = data
+        .get(1)
+        .cloned()
+        .flatten()
+        .and_then(|x| x.parse::<f64>().ok())
+        .unwrap_or(0.0);
+
+    let staleness = if last_scraped == 0 {
+        NEVER_SCRAPED_STALENESS
+    } else {
+        ((now - last_scraped) as f64 / (60.0 * 60.0 * 24.0)).clamp(0.0, MAX_STALENESS)
+    };
+
+    let inbound = db
+        .scard(&format!("pages:linkedfrom:{}", page))
+        .await
+        .unwrap_or(0);
+    let inbound = (1.0 + (inbound + new_inbound) as f64).ln() * INBOUND_WEIGHT;
+
+    Ok(staleness + inbound + boost)
+}
+
+/// Adds a page to the queue, or updates its priority if it's already there.
+pub async fn enqueue(db: &dyn Storage, page: &str) -> anyhow::Result<()> {
+    db.apply(vec![enqueue_op(db, page, 0).await?]).await
+}
+
+/// The write behind [`enqueue`], for applying alongside others.
+pub async fn enqueue_op(db: &dyn Storage, page: &str, new_inbound: usize) -> anyhow::Result<Op> {
+    let priority = priority(db, page, new_inbound).await?;
+    Ok(Op::ZAdd(
+        "pages:queue".to_string(),
+        priority,
+        page.to_string(),
+    ))
+}
+
+/// Returns a window of the highest-priority pages in the queue, starting at `offset`.
+pub async fn peek(db: &dyn Storage, offset: usize, count: usize) -> anyhow::Result<Vec<String>> {
+    db.zrevrange("pages:queue", offset as i64, (offset + count) as i64 - 1)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::collections::HashMap;
+
+    const DAY: i64 = 60 * 60 * 24;
+
+    async fn scraped(db: &dyn Storage, page: &str, last_scraped: i64) {
+        db.hset(
+            &format!("pages:data:{}", page),
+            HashMap::from_iter(vec![("lastScraped".to_string(), last_scraped.to_string())]),
+        )
+        .await
+        .unwrap();
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 0.01
+    }
+
+    #[tokio::test]
+    async fn priority_weighs_staleness_links_and_boosts() {
+        let db = MemoryStorage::open(None).unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        assert!(close(
+            priority(&db, "new", 0).await.unwrap(),
+            NEVER_SCRAPED_STALENESS
+        ));
+
+        scraped(&db, "week", now - 7 * DAY).await;
+        assert!(close(priority(&db, "week", 0).await.unwrap(), 7.0));
+
+        scraped(&db, "ancient", now - 10_000 * DAY).await;
+        assert!(close(
+            priority(&db, "ancient", 0).await.unwrap(),
+            MAX_STALENESS
+        ));
+
+        // Stored links and ones about to be written count the same
+        scraped(&db, "linked", now).await;
+        for from in ["a", "b"] {
+            db.sadd("pages:linkedfrom:linked", from).await.unwrap();
+        }
+        let expected = 4f64.ln() * INBOUND_WEIGHT;
+        assert!(close(priority(&db, "linked", 1).await.unwrap(), expected));
+
+        db.hset(
+            "pages:data:week",
+            HashMap::from_iter(vec![("boost".to_string(), "100".to_string())]),
+        )
+        .await
+        .unwrap();
+        assert!(close(priority(&db, "week", 0).await.unwrap(), 107.0));
+    }
+
+    #[tokio::test]
+    async fn never_scraped_pages_come_first() {
+        let db = MemoryStorage::open(None).unwrap();
+        let now = chrono::Utc::now().timestamp();
+        scraped(&db, "old", now - 10_000 * DAY).await;
+        scraped(&db, "recent", now - DAY).await;
+
+        for page in ["recent", "old", "new"] {
+            enqueue(&db, page).await.unwrap();
+        }
+        assert_eq!(peek(&db, 0, 3).await.unwrap(), vec!["new", "old", "recent"]);
+    }
+}
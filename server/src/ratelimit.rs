@@ -0,0 +1,308 @@
+use crate::{keys, AppResult, AppState};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header::RETRY_AFTER, Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How many opt-out requests a client address can make per hour if the config doesn't say
+pub const DEFAULT_OPT_OUTS: u64 = 20;
+
+/// How often buckets that have refilled are dropped, in seconds
+const PRUNE_INTERVAL: u64 = 60;
+
+/// How many searches a client address can make per hour if the config doesn't say
+pub const DEFAULT_SEARCHES: u64 = 600;
+
+/// Per-key limits, each an amount per hour. Anything left out is unlimited,
+/// except opt-outs and searches, and the admin key is never limited.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RateLimits {
+    pub work_claims: Option<u64>,
+    pub work_posts: Option<u64>,
+    pub badge_bytes: Option<u64>,
+    /// Requests to `/optout` and `/optout/verify`, counted per client address
+    pub opt_outs: Option<u64>,
+    /// Requests to `/search`, counted per client address
+    pub searches: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum Kind {
+    WorkClaims,
+    WorkPosts,
+    BadgeBytes,
+    OptOuts,
+    Searches,
+}
+
+impl Kind {
+    fn limit(self, limits: Option<&RateLimits>) -> Option<u64> {
+        match self {
+            Kind::WorkClaims => limits?.work_claims,
+            Kind::WorkPosts => limits?.work_posts,
+            Kind::BadgeBytes => limits?.badge_bytes,
+            // These come from anyone on the Internet, so they're always limited
+            Kind::OptOuts => Some(limits.and_then(|x| x.opt_outs).unwrap_or(DEFAULT_OPT_OUTS)),
+            Kind::Searches => Some(limits.and_then(|x| x.searches).unwrap_or(DEFAULT_SEARCHES)),
+        }
+    }
+}
+
+/// A bucket holding up to an hour's allowance, refilling continuously.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: u64) {
+        let now = Instant::now();
+        let per_second = limit as f64 / 3600.0;
+        self.tokens =
+            (self.tokens + (now - self.updated).as_secs_f64() * per_second).min(limit as f64);
+        self.updated = now;
+    }
+
+    /// How long until the bucket holds `amount`.
+    fn wait(&self, limit: u64, amount: u64) -> Duration {
+        let per_second = limit as f64 / 3600.0;
+        let wait = (amount as f64 - self.tokens) / per_second;
+        Duration::from_secs_f64(wait.max(1.0))
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    pub limit: u64,
+    /// How much of the last hour's allowance has been used
+    pub used: u64,
+    pub remaining: u64,
+}
+
+struct Buckets {
+    buckets: HashMap<(String, Kind), Bucket>,
+    pruned: Instant,
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            pruned: Instant::now(),
+        }
+    }
+}
+
+impl Buckets {
+    /// Drops buckets that have refilled, since a full bucket is the same as no bucket.
+    fn prune(&mut self, limits: Option<&RateLimits>) {
+        self.pruned = Instant::now();
+        self.buckets.retain(|(_, kind), bucket| {
+            let Some(limit) = kind.limit(limits) else {
+                return false;
+            };
+            bucket.refill(limit);
+            bucket.tokens < limit as f64
+        });
+    }
+}
+
+/// Token buckets for every key that has made a limited request, kept in memory.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Looks up the limit for `kind`, or `None` if the key isn't limited.
+    fn limit(state: &AppState, id: &str, kind: Kind) -> Option<u64> {
+        if id == keys::hash(state, &state.config.admin_key) {
+            return None;
+        }
+
+        kind.limit(state.config.rate_limits.as_ref())
+    }
+
+    fn with_bucket<T>(
+        &self,
+        state: &AppState,
+        id: &str,
+        kind: Kind,
+        limit: u64,
+        f: impl FnOnce(&mut Bucket) -> T,
+    ) -> T {
+        let mut buckets = self.buckets.lock().unwrap();
+        // Otherwise every address that ever searched would be kept forever
+        if buckets.pruned.elapsed() >= Duration::from_secs(PRUNE_INTERVAL) {
+            buckets.prune(state.config.rate_limits.as_ref());
+        }
+
+        let bucket = buckets
+            .buckets
+            .entry((id.to_string(), kind))
+            .or_insert_with(|| Bucket {
+                tokens: limit as f64,
+                updated: Instant::now(),
+            });
+        bucket.refill(limit);
+        f(bucket)
+    }
+
+    /// Takes up to `wanted` from the key's bucket, returning how much was granted.
+    ///
+    /// When nothing could be granted, also returns how long until there will be.
+    pub fn take(
+        &self,
+        state: &AppState,
+        id: &str,
+        kind: Kind,
+        wanted: u64,
+    ) -> (u64, Option<Duration>) {
+        let Some(limit) = Self::limit(state, id, kind) else {
+            return (wanted, None);
+        };
+        if limit == 0 {
+            return (0, Some(Duration::from_secs(3600)));
+        }
+
+        self.with_bucket(state, id, kind, limit, |bucket| {
+            let granted = (bucket.tokens.floor() as u64).min(wanted);
+            bucket.tokens -= granted as f64;
+            if granted > 0 || wanted == 0 {
+                return (granted, None);
+            }
+
+            (0, Some(bucket.wait(limit, 1)))
+        })
+    }
+
+    /// Takes exactly `amount`, or nothing at all if there isn't enough.
+    pub fn take_all(
+        &self,
+        state: &AppState,
+        id: &str,
+        kind: Kind,
+        amount: u64,
+    ) -> Result<(), Duration> {
+        let Some(limit) = Self::limit(state, id, kind) else {
+            return Ok(());
+        };
+
+        // A single request bigger than the whole allowance could never go through
+        if amount > limit {
+            return Err(Duration::from_secs(3600));
+        }
+
+        self.with_bucket(state, id, kind, limit, |bucket| {
+            if bucket.tokens >= amount as f64 {
+                bucket.tokens -= amount as f64;
+                return Ok(());
+            }
+
+            Err(bucket.wait(limit, amount))
+        })
+    }
+
+    /// Puts back what was taken but went unused, like claims on an empty queue.
+    pub fn refund(&self, state: &AppState, id: &str, kind: Kind, amount: u64) {
+        let Some(limit) = Self::limit(state, id, kind) else {
+            return;
+        };
+
+        self.with_bucket(state, id, kind, limit, |bucket| {
+            bucket.tokens = (bucket.tokens + amount as f64).min(limit as f64);
+        });
+    }
+
+    fn usage(&self, state: &AppState) -> BTreeMap<String, BTreeMap<Kind, Usage>> {
+        let limits = state.config.rate_limits.clone();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.prune(limits.as_ref());
+
+        let mut usage: BTreeMap<String, BTreeMap<Kind, Usage>> = BTreeMap::new();
+        for ((id, kind), bucket) in &buckets.buckets {
+            let Some(limit) = kind.limit(limits.as_ref()) else {
+                continue;
+            };
+
+            let remaining = bucket.tokens.floor() as u64;
+            usage.entry(id.clone()).or_default().insert(
+                *kind,
+                Usage {
+                    limit,
+                    used: limit - remaining,
+                    remaining,
+                },
+            );
+        }
+
+        usage
+    }
+}
+
+pub fn too_many_requests(retry_after: Duration) -> Response<Body> {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after.as_secs().max(1).to_string())],
+    )
+        .into_response()
+}
+
+/// Shows how much of their allowance each key has used, by key id (or client
+/// address for opt-outs and searches).
+pub async fn usage(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    Ok(Json(state.limiter.usage(&state)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_buckets_are_dropped() {
+        let state = AppState::test(serde_json::json!({}));
+        let limiter = RateLimiter::default();
+        for i in 0..10 {
+            let id = format!("ip:10.0.0.{}", i);
+            assert!(limiter.take_all(&state, &id, Kind::Searches, 1).is_ok());
+        }
+
+        // An hour on, they've all refilled
+        {
+            let mut buckets = limiter.buckets.lock().unwrap();
+            let an_hour_ago = Instant::now() - Duration::from_secs(3600);
+            for bucket in buckets.buckets.values_mut() {
+                bucket.updated = an_hour_ago;
+            }
+            buckets.pruned = an_hour_ago;
+        }
+
+        assert!(limiter
+            .take_all(&state, "ip:10.0.1.0", Kind::Searches, 1)
+            .is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(
+            buckets.buckets.keys().collect::<Vec<_>>(),
+            vec![&("ip:10.0.1.0".to_string(), Kind::Searches)]
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{
+    denylist, failures, get_domain, politeness, queue,
+    storage::{Op, Storage},
+    AppResult, AppState,
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+};
+use axum_auth::AuthBearer;
+use base64::Engine;
+
+/// How long until a scraped page is due to be scraped again, in seconds
+pub const DEFAULT_RECRAWL_INTERVAL: u64 = 60 * 60 * 24 * 7;
+
+/// How often the scheduler checks for pages that are due
+const SCHEDULE_INTERVAL: u64 = 60;
+
+/// How many pages to read at once, and write back in one go
+const SCHEDULE_BATCH: usize = 500;
+
+pub fn recrawl_interval(state: &AppState) -> i64 {
+    state
+        .config
+        .recrawl_interval
+        .unwrap_or(DEFAULT_RECRAWL_INTERVAL) as i64
+}
+
+/// Schedules `page` to be scraped again at the `due` timestamp.
+///
+/// Due dates live in the `pages:recrawl` sorted set, scored by timestamp.
+pub fn schedule_op(page: &str, due: i64) -> Op {
+    Op::ZAdd("pages:recrawl".to_string(), due as f64, page.to_string())
+}
+
+pub async fn unschedule(db: &dyn Storage, page: &str) -> anyhow::Result<()> {
+    db.apply(vec![unschedule_op(page)]).await
+}
+
+pub fn unschedule_op(page: &str) -> Op {
+    Op::ZRem("pages:recrawl".to_string(), page.to_string())
+}
+
+/// Works out how to move a page that's due for a recrawl onto the queue.
+async fn requeue_op(db: &dyn Storage, state: &AppState, page: &str) -> anyhow::Result<Option<Op>> {
+    let redirect = db.get(&format!("redirect:{}", page)).await.unwrap_or(None);
+    let page = redirect.as_deref().unwrap_or(page);
+
+    // Safety check here just in case
+    let url = String::from_utf8(state.base64.decode(page)?)?;
+    if let Some(domain) = get_domain(&url) {
+        if denylist::is_denylisted(db, state, &domain).await? {
+            return Ok(None);
+        }
+    }
+
+    // Still disallowed by robots.txt, so check back once that's run out
+    if let Some(until) = politeness::disallowed_until(db, state, page).await? {
+        return Ok(Some(schedule_op(page, until)));
+    }
+
+    Ok(Some(queue::enqueue_op(db, page, 0).await?))
+}
+
+/// Enqueues every page that has passed its recrawl date, a batch at a time.
+async fn enqueue_due(state: &AppState) -> anyhow::Result<usize> {
+    let db = &*state.db;
+    let now = chrono::Utc::now().timestamp();
+    let mut count = 0;
+
+    loop {
+        let due = db
+            .zrangebyscore("pages:recrawl", now as f64, Some(SCHEDULE_BATCH))
+            .await?;
+
+        let requeues =
+            futures::future::try_join_all(due.iter().map(|page| requeue_op(db, state, page)))
+                .await?;
+        // Unscheduled first, so pages that aren't ready yet can be scheduled again
+        let mut ops: Vec<Op> = due.iter().map(|page| unschedule_op(page)).collect();
+        ops.extend(requeues.into_iter().flatten());
+        db.apply(ops).await?;
+
+        count += due.len();
+        if due.len() < SCHEDULE_BATCH {
+            break;
+        }
+    }
+
+    Ok(count)
+}
+
+pub async fn scheduler(state: AppState) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(SCHEDULE_INTERVAL));
+    loop {
+        interval.tick().await;
+
+        match enqueue_due(&state).await {
+            Ok(0) => {}
+            Ok(count) => println!("Queued {} pages for recrawl", count),
+            Err(e) => eprintln!("Failed to queue recrawls: {}", e),
+        }
+    }
+}
+
+/// Rebuilds the recrawl schedule from every known page.
+///
+/// This doesn't touch the queue - pages that are due get picked up by the scheduler.
+pub async fn reschedule(state: &AppState) -> anyhow::Result<()> {
+    println!("Rebuilding recrawl schedule...");
+
+    let db = &*state.db;
+    let pages = db.smembers("pages").await?;
+
+    for chunk in pages.chunks(SCHEDULE_BATCH) {
+        let data = futures::future::try_join_all(chunk.iter().map(|page| async move {
+            db.hmget(
+                &format!("pages:data:{}", page),
+                &["lastScraped", "failures"],
+            )
+            .await
+        }))
+        .await?;
+        let dead = futures::future::try_join_all(
+            chunk.iter().map(|page| db.sismember("pages:dead", page)),
+        )
+        .await?;
+
+        let mut ops = Vec::new();
+        for ((page, data), dead) in chunk.iter().zip(data).zip(dead) {
+            // Pages that kept failing stay off the schedule until something revives them
+            if dead {
+                ops.push(unschedule_op(page));
+                continue;
+            }
+
+            let field = |i: usize| {
+                data.get(i)
+                    .cloned()
+                    .flatten()
+                    .and_then(|x| x.parse::<i64>().ok())
+                    .unwrap_or(0)
+            };
+            let last_scraped = field(0);
+            let failures = field(1) as u64;
+
+            // Pages that have never been scraped are due immediately
+            let due = if last_scraped == 0 {
+                0
+            } else if failures > 0 {
+                last_scraped + failures::backoff(state, failures)
+            } else {
+                last_scraped + recrawl_interval(state)
+            };
+
+            ops.push(schedule_op(page, due));
+        }
+        db.apply(ops).await?;
+    }
+
+    println!("Recrawl schedule rebuilt with {} pages", pages.len());
+    Ok(())
+}
+
+/// Brings databases from before the recrawl schedule existed up to date.
+pub async fn migrate(state: &AppState) -> anyhow::Result<()> {
+    let db = &*state.db;
+
+    // The queue used to be a list
+    if db.zcard("pages:queue").await.is_err() {
+        println!("Dropping old list-based queue");
+        db.del("pages:queue").await?;
+    }
+
+    if db.exists("pages:recrawl").await? {
+        return Ok(());
+    }
+
+    reschedule(state).await
+}
+
+pub async fn reschedule_handler(
+    State(state): State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    reschedule(&state).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
@@ -0,0 +1,311 @@
+use crate::{
+    get_domain, graph, ratelimit,
+    storage::{Op, Storage},
+    AppResult, AppState,
+};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Query, State},
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+};
+
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+/// How many times a pair's inbound count is checked again when another result changes it meanwhile
+const SETTLE_ATTEMPTS: usize = 5;
+const MAX_SEARCH_LIMIT: usize = 100;
+
+/// Substring matches scan the whole index, so very short queries only match prefixes
+const MIN_SUBSTRING_LENGTH: usize = 3;
+
+/// How many matching domains to consider before ranking
+const MAX_CANDIDATES: usize = 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    pub domain: String,
+    /// How many other domains link to this one
+    pub inbound: u64,
+}
+
+/// Adds a domain to the search index if it isn't there already.
+///
+/// Every domain is scored 0 in `search:domains`, so it can be range queried by prefix.
+pub async fn index_domain(db: &dyn Storage, domain: &str) -> anyhow::Result<()> {
+    db.apply(vec![index_domain_op(domain)]).await
+}
+
+pub fn index_domain_op(domain: &str) -> Op {
+    Op::ZAddNx("search:domains".to_string(), 0.0, domain.to_string())
+}
+
+/// The writes for pages on one domain gaining or losing live links to pages on
+/// another, given as a change in link count for each `(from, to)` pair of domains.
+///
+/// How many live links there are is kept per pair in `domain:linkcounts:{to}`,
+/// and `from` only counts towards `to`'s inbound count while it has at least one.
+/// The counts are only incremented here, so results committed at the same time
+/// add up - [`settle_link_counts`] brings the inbound counts in line afterwards.
+pub fn link_count_ops(state: &AppState, changes: &HashMap<(String, String), i64>) -> Vec<Op> {
+    changes
+        .iter()
+        .filter(|((from, to), change)| from != to && **change != 0)
+        .map(|((from, to), change)| {
+            let to_b64 = state.base64.encode(to.as_bytes());
+            Op::HIncrBy(
+                format!("domain:linkcounts:{}", to_b64),
+                from.clone(),
+                *change,
+            )
+        })
+        .collect()
+}
+
+/// Updates which domains link to which, and the inbound counts searches are ranked by,
+/// once [`link_count_ops`] have been applied.
+pub async fn settle_link_counts(
+    db: &dyn Storage,
+    state: &AppState,
+    changes: &HashMap<(String, String), i64>,
+) -> anyhow::Result<()> {
+    for ((from, to), change) in changes {
+        if from == to || *change == 0 {
+            continue;
+        }
+
+        let to_b64 = state.base64.encode(to.as_bytes());
+        let counts_key = format!("domain:linkcounts:{}", to_b64);
+        let linked_key = format!("domain:linkedfrom:{}", to_b64);
+        let mut settled = false;
+        for _ in 0..SETTLE_ATTEMPTS {
+            let mut watch = db.watch().await?;
+            watch
+                .watch(&[counts_key.clone(), linked_key.clone()])
+                .await?;
+            let count = db.hget(&counts_key, from).await?;
+            let linked = db.sismember(&linked_key, from).await?;
+
+            let mut ops = Vec::new();
+            let live = count
+                .as_deref()
+                .and_then(|x| x.parse::<i64>().ok())
+                .is_some_and(|x| x > 0);
+            if !live && count.is_some() {
+                ops.push(Op::HDel(counts_key.clone(), from.clone()));
+            }
+            if live && !linked {
+                ops.push(Op::SAdd(linked_key.clone(), from.clone()));
+                ops.push(Op::ZIncrBy("search:inbound".to_string(), 1.0, to.clone()));
+            } else if !live && linked {
+                ops.push(Op::SRem(linked_key.clone(), from.clone()));
+                ops.push(Op::ZIncrBy("search:inbound".to_string(), -1.0, to.clone()));
+            }
+
+            if watch.apply(ops).await? {
+                settled = true;
+                break;
+            }
+        }
+
+        if !settled {
+            eprintln!("Gave up updating links from {} to {}", from, to);
+        }
+    }
+
+    Ok(())
+}
+
+/// Fills the search index from the links recorded before it existed.
+pub async fn migrate(state: &AppState) -> anyhow::Result<()> {
+    let db = &*state.db;
+    if !db.sismember("migrations", "search-index").await? {
+        println!("Building search index...");
+        let graph = graph::build(state, None).await?;
+        for domain in graph.links_to.keys() {
+            index_domain(db, domain).await?;
+        }
+
+        db.sadd("migrations", "search-index").await?;
+        println!("Indexed {} domains for search", graph.links_to.len());
+    }
+
+    if !db.sismember("migrations", "search-link-counts").await? {
+        println!("Counting links between domains...");
+        count_links(state).await?;
+        db.sadd("migrations", "search-link-counts").await?;
+    }
+
+    Ok(())
+}
+
+/// Counts the live links between every pair of domains, resetting inbound counts to match.
+///
+/// Inbound counts used to only ever go up, so this also drops domains that
+/// stopped linking before the counts were kept.
+async fn count_links(state: &AppState) -> anyhow::Result<()> {
+    let db = &*state.db;
+
+    // Keyed by the domain linked to, then the domain linking to it
+    let mut counts: HashMap<String, HashMap<String, i64>> = HashMap::new();
+    let pages = db.smembers("pages").await?;
+    for chunk in pages.chunks(graph::BUILD_BATCH) {
+        let fetched = futures::future::try_join_all(
+            chunk
+                .iter()
+                .map(|page| graph::fetch_page(db, state, page, None)),
+        )
+        .await?;
+
+        for page in fetched.into_iter().flatten() {
+            for (url, _) in page.links_to {
+                let Some(to) = get_domain(&url).filter(|x| *x != page.domain) else {
+                    continue;
+                };
+                *counts
+                    .entry(to)
+                    .or_default()
+                    .entry(page.domain.clone())
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    for domain in db.zrange("search:inbound", 0, -1).await? {
+        if counts.contains_key(&domain) {
+            continue;
+        }
+
+        let domain_b64 = state.base64.encode(domain.as_bytes());
+        db.apply(vec![
+            Op::ZRem("search:inbound".to_string(), domain),
+            Op::Del(format!("domain:linkedfrom:{}", domain_b64)),
+            Op::Del(format!("domain:linkcounts:{}", domain_b64)),
+        ])
+        .await?;
+    }
+
+    for (to, from) in &counts {
+        let to_b64 = state.base64.encode(to.as_bytes());
+        let counts_key = format!("domain:linkcounts:{}", to_b64);
+        let linked_key = format!("domain:linkedfrom:{}", to_b64);
+
+        let mut ops = vec![
+            Op::Del(counts_key.clone()),
+            Op::Del(linked_key.clone()),
+            Op::HSet(
+                counts_key,
+                from.iter()
+                    .map(|(domain, count)| (domain.clone(), count.to_string()))
+                    .collect(),
+            ),
+        ];
+        for domain in from.keys() {
+            ops.push(Op::SAdd(linked_key.clone(), domain.clone()));
+        }
+        ops.push(Op::ZAdd(
+            "search:inbound".to_string(),
+            from.len() as f64,
+            to.clone(),
+        ));
+        db.apply(ops).await?;
+    }
+
+    println!("Counted links to {} domains", counts.len());
+    Ok(())
+}
+
+/// Finds domains starting with or containing `query`, most linked to first.
+pub async fn find(
+    db: &dyn Storage,
+    query: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<SearchResult>> {
+    let mut candidates = db
+        .zrangebylex(
+            "search:domains",
+            query,
+            // DEL sorts after every character that can appear in a domain
+            &format!("{}\x7f", query),
+            MAX_CANDIDATES,
+        )
+        .await?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+
+    if query.len() >= MIN_SUBSTRING_LENGTH {
+        let matches = db.zscan("search:domains", &format!("*{}*", query)).await?;
+        candidates.extend(matches.into_iter().take(MAX_CANDIDATES));
+    }
+
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let candidates = candidates.into_iter().collect::<Vec<_>>();
+    let inbound = db.zmscore("search:inbound", &candidates).await?;
+
+    let mut results = candidates
+        .into_iter()
+        .zip(inbound)
+        .map(|(domain, inbound)| SearchResult {
+            domain,
+            inbound: inbound.unwrap_or(0.0) as u64,
+        })
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| {
+        b.inbound
+            .cmp(&a.inbound)
+            .then_with(|| a.domain.cmp(&b.domain))
+    });
+    results.truncate(limit);
+
+    Ok(results)
+}
+
+pub async fn search(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<SearchQuery>,
+) -> AppResult<Response<Body>> {
+    // Anyone can search, and substring matches scan the whole index
+    let id = format!("ip:{}", addr.ip());
+    if let Err(wait) = state
+        .limiter
+        .take_all(&state, &id, ratelimit::Kind::Searches, 1)
+    {
+        return Ok(ratelimit::too_many_requests(wait));
+    }
+
+    // Domains are stored in their ASCII form, which also keeps the query safe to use as a pattern
+    let q = query.q.trim().to_lowercase();
+    if q.is_empty()
+        || !q
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .min(MAX_SEARCH_LIMIT);
+
+    let db = &*state.db;
+    let results = find(db, &q, limit).await?;
+    Ok(Json(results).into_response())
+}
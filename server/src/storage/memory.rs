@@ -0,0 +1,1215 @@
+use super::{Expiry, Op, Storage, Watch};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    ops::Range,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// How often changes are written to `storage_path`, in seconds
+const PERSIST_INTERVAL: u64 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "value", rename_all = "camelCase")]
+enum Value {
+    String(String),
+    Set(HashSet<String>),
+    Hash(HashMap<String, String>),
+    SortedSet(SortedSet),
+    List(VecDeque<String>),
+    /// Stands in for a HyperLogLog. Counts are exact, which is fine at the sizes this is for.
+    Log(HashSet<String>),
+}
+
+/// Which of [`Value`]'s types a key holds, for checking writes before making them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    String,
+    Set,
+    Hash,
+    SortedSet,
+    List,
+    Log,
+}
+
+impl Value {
+    fn kind(&self) -> Kind {
+        match self {
+            Value::String(_) => Kind::String,
+            Value::Set(_) => Kind::Set,
+            Value::Hash(_) => Kind::Hash,
+            Value::SortedSet(_) => Kind::SortedSet,
+            Value::List(_) => Kind::List,
+            Value::Log(_) => Kind::Log,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::Set(x) | Value::Log(x) => x.is_empty(),
+            Value::Hash(x) => x.is_empty(),
+            Value::SortedSet(x) => x.scores.is_empty(),
+            Value::List(x) => x.is_empty(),
+        }
+    }
+
+    fn string(&self) -> Option<&String> {
+        match self {
+            Value::String(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn set(&self) -> Option<&HashSet<String>> {
+        match self {
+            Value::Set(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn set_mut(&mut self) -> Option<&mut HashSet<String>> {
+        match self {
+            Value::Set(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn hash(&self) -> Option<&HashMap<String, String>> {
+        match self {
+            Value::Hash(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn hash_mut(&mut self) -> Option<&mut HashMap<String, String>> {
+        match self {
+            Value::Hash(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn sorted_set(&self) -> Option<&SortedSet> {
+        match self {
+            Value::SortedSet(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn sorted_set_mut(&mut self) -> Option<&mut SortedSet> {
+        match self {
+            Value::SortedSet(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn list(&self) -> Option<&VecDeque<String>> {
+        match self {
+            Value::List(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn list_mut(&mut self) -> Option<&mut VecDeque<String>> {
+        match self {
+            Value::List(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn log(&self) -> Option<&HashSet<String>> {
+        match self {
+            Value::Log(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn log_mut(&mut self) -> Option<&mut HashSet<String>> {
+        match self {
+            Value::Log(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    value: Value,
+    /// Unix timestamp in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<i64>,
+    /// When this was last written, as a [`Data::revision`], for [`MemoryWatch`]
+    #[serde(skip)]
+    version: u64,
+}
+
+/// Orders scores the same way Redis does, so sorted sets can live in a `BTreeSet`.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score then name, saved as just the scores.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(from = "HashMap<String, f64>", into = "HashMap<String, f64>")]
+struct SortedSet {
+    scores: HashMap<String, f64>,
+    order: BTreeSet<(Score, String)>,
+}
+
+impl From<HashMap<String, f64>> for SortedSet {
+    fn from(scores: HashMap<String, f64>) -> Self {
+        let order = scores
+            .iter()
+            .map(|(member, score)| (Score(*score), member.clone()))
+            .collect();
+        Self { scores, order }
+    }
+}
+
+impl From<SortedSet> for HashMap<String, f64> {
+    fn from(set: SortedSet) -> Self {
+        set.scores
+    }
+}
+
+impl SortedSet {
+    fn insert(&mut self, member: &str, score: f64) {
+        if let Some(old) = self.scores.insert(member.to_string(), score) {
+            self.order.remove(&(Score(old), member.to_string()));
+        }
+        self.order.insert((Score(score), member.to_string()));
+    }
+
+    fn remove(&mut self, member: &str) -> bool {
+        let Some(score) = self.scores.remove(member) else {
+            return false;
+        };
+        self.order.remove(&(Score(score), member.to_string()));
+        true
+    }
+}
+
+#[derive(Default)]
+struct Data {
+    entries: HashMap<String, Entry>,
+    /// Whether anything has changed since the last save
+    dirty: bool,
+    /// Counts up with every write
+    revision: u64,
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn wrong_type() -> anyhow::Error {
+    anyhow::anyhow!("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+/// Matches `*` wildcards, which is all the patterns used here need.
+fn glob(pattern: &str, s: &str) -> bool {
+    let parts = pattern.split('*').collect::<Vec<_>>();
+    if parts.len() == 1 {
+        return pattern == s;
+    }
+
+    let Some(mut rest) = s.strip_prefix(parts[0]) else {
+        return false;
+    };
+    let last = parts[parts.len() - 1];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Turns a Redis-style inclusive range, where negative indexes count from the end, into indexes.
+fn range(len: usize, start: i64, stop: i64) -> Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop {
+        return 0..0;
+    }
+
+    start as usize..stop as usize + 1
+}
+
+impl Data {
+    /// Drops `key` if it has expired, so it behaves as if it doesn't exist.
+    fn expire(&mut self, key: &str) {
+        let expired = self
+            .entries
+            .get(key)
+            .and_then(|x| x.expires)
+            .is_some_and(|x| x <= now());
+        if expired {
+            self.entries.remove(key);
+            self.dirty = true;
+        }
+    }
+
+    fn read<C: Default, T>(
+        &mut self,
+        key: &str,
+        cast: fn(&Value) -> Option<&C>,
+        f: impl FnOnce(&C) -> T,
+    ) -> anyhow::Result<T> {
+        self.expire(key);
+        match self.entries.get(key) {
+            Some(entry) => Ok(f(cast(&entry.value).ok_or_else(wrong_type)?)),
+            None => Ok(f(&C::default())),
+        }
+    }
+
+    /// Changes the value at `key`, creating it first if needed. Collections
+    /// left empty are deleted, like in Redis.
+    fn write<C, T>(
+        &mut self,
+        key: &str,
+        new: fn() -> Value,
+        cast: fn(&mut Value) -> Option<&mut C>,
+        f: impl FnOnce(&mut C) -> T,
+    ) -> anyhow::Result<T> {
+        self.expire(key);
+        let entry = self
+            .entries
+            .entry(key.to_string())
+            .or_insert_with(|| Entry {
+                value: new(),
+                expires: None,
+                version: 0,
+            });
+        let result = f(cast(&mut entry.value).ok_or_else(wrong_type)?);
+        self.revision += 1;
+        entry.version = self.revision;
+
+        if entry.value.is_empty() {
+            self.entries.remove(key);
+        }
+        self.dirty = true;
+        Ok(result)
+    }
+
+    fn set(&mut self, key: &str, value: &str, expiry: Option<Expiry>) {
+        let expires = expiry.map(|x| match x {
+            Expiry::Seconds(x) => now() + x * 1000,
+            Expiry::Millis(x) => now() + x,
+        });
+        self.revision += 1;
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                value: Value::String(value.to_string()),
+                expires,
+                version: self.revision,
+            },
+        );
+        self.dirty = true;
+    }
+
+    fn del(&mut self, key: &str) -> bool {
+        self.expire(key);
+        let removed = self.entries.remove(key).is_some();
+        self.dirty |= removed;
+        removed
+    }
+
+    fn sadd(&mut self, key: &str, member: &str) -> anyhow::Result<bool> {
+        self.write(
+            key,
+            || Value::Set(HashSet::new()),
+            Value::set_mut,
+            |x| x.insert(member.to_string()),
+        )
+    }
+
+    fn srem(&mut self, key: &str, member: &str) -> anyhow::Result<bool> {
+        self.write(
+            key,
+            || Value::Set(HashSet::new()),
+            Value::set_mut,
+            |x| x.remove(member),
+        )
+    }
+
+    fn hset(&mut self, key: &str, fields: HashMap<String, String>) -> anyhow::Result<()> {
+        if fields.is_empty() {
+            return Ok(());
+        }
+
+        self.write(
+            key,
+            || Value::Hash(HashMap::new()),
+            Value::hash_mut,
+            |x| x.extend(fields),
+        )
+    }
+
+    fn hdel(&mut self, key: &str, field: &str) -> anyhow::Result<bool> {
+        self.write(
+            key,
+            || Value::Hash(HashMap::new()),
+            Value::hash_mut,
+            |x| x.remove(field).is_some(),
+        )
+    }
+
+    fn hincrby(&mut self, key: &str, field: &str, by: i64) -> anyhow::Result<i64> {
+        self.write(
+            key,
+            || Value::Hash(HashMap::new()),
+            Value::hash_mut,
+            |x| -> anyhow::Result<i64> {
+                let value = x
+                    .entry(field.to_string())
+                    .or_insert_with(|| "0".to_string());
+                let sum = value.parse::<i64>()? + by;
+                *value = sum.to_string();
+                Ok(sum)
+            },
+        )?
+    }
+
+    fn pfadd(&mut self, key: &str, member: &str) -> anyhow::Result<()> {
+        self.write(
+            key,
+            || Value::Log(HashSet::new()),
+            Value::log_mut,
+            |x| {
+                x.insert(member.to_string());
+            },
+        )
+    }
+
+    fn zadd(&mut self, key: &str, score: f64, member: &str, only_new: bool) -> anyhow::Result<()> {
+        self.write(
+            key,
+            || Value::SortedSet(SortedSet::default()),
+            Value::sorted_set_mut,
+            |x| {
+                if !only_new || !x.scores.contains_key(member) {
+                    x.insert(member, score);
+                }
+            },
+        )
+    }
+
+    fn zrem(&mut self, key: &str, member: &str) -> anyhow::Result<bool> {
+        self.write(
+            key,
+            || Value::SortedSet(SortedSet::default()),
+            Value::sorted_set_mut,
+            |x| x.remove(member),
+        )
+    }
+
+    fn zincrby(&mut self, key: &str, by: f64, member: &str) -> anyhow::Result<f64> {
+        self.write(
+            key,
+            || Value::SortedSet(SortedSet::default()),
+            Value::sorted_set_mut,
+            |x| {
+                let score = x.scores.get(member).copied().unwrap_or(0.0) + by;
+                x.insert(member, score);
+                score
+            },
+        )
+    }
+
+    /// Identifies what's at `key` right now, changing whenever it's written to.
+    fn version(&mut self, key: &str) -> Option<u64> {
+        self.expire(key);
+        self.entries.get(key).map(|x| x.version)
+    }
+
+    /// Finds any error `ops` would run into, so [`Data::run`] can't stop partway through.
+    fn check(&mut self, ops: &[Op]) -> anyhow::Result<()> {
+        // What's at each key once the ops before the current one have run, where they've changed it
+        let mut kinds: HashMap<&str, Option<Kind>> = HashMap::new();
+        // Hash fields set earlier on, or `None` when they're known to hold a number
+        let mut fields: HashMap<(&str, &str), Option<&str>> = HashMap::new();
+        // Keys deleted or overwritten earlier on, so nothing stored there matters any more
+        let mut replaced = HashSet::new();
+
+        for op in ops {
+            let (key, kind) = match op {
+                Op::Set(key, ..) => (key, None),
+                Op::Del(key) => (key, None),
+                Op::SAdd(key, _) | Op::SRem(key, _) => (key, Some(Kind::Set)),
+                Op::HSet(key, _) | Op::HDel(key, _) | Op::HIncrBy(key, ..) => {
+                    (key, Some(Kind::Hash))
+                }
+                Op::ZAdd(key, ..)
+                | Op::ZAddNx(key, ..)
+                | Op::ZRem(key, _)
+                | Op::ZIncrBy(key, ..) => (key, Some(Kind::SortedSet)),
+                Op::PfAdd(key, _) => (key, Some(Kind::Log)),
+            };
+            let key = key.as_str();
+
+            let Some(kind) = kind else {
+                // Replaces whatever was there
+                kinds.insert(key, matches!(op, Op::Set(..)).then_some(Kind::String));
+                fields.retain(|(x, _), _| *x != key);
+                replaced.insert(key);
+                continue;
+            };
+
+            let current = match kinds.get(key) {
+                Some(x) => *x,
+                None => {
+                    self.expire(key);
+                    self.entries.get(key).map(|x| x.value.kind())
+                }
+            };
+            if current.is_some_and(|x| x != kind) {
+                return Err(wrong_type());
+            }
+
+            match op {
+                Op::HSet(_, values) => {
+                    for (field, value) in values {
+                        fields.insert((key, field), Some(value));
+                    }
+                }
+                Op::HDel(_, field) => {
+                    fields.insert((key, field), None);
+                }
+                Op::HIncrBy(_, field, _) => {
+                    let value = match fields.get(&(key, field.as_str())) {
+                        Some(x) => x.map(str::to_string),
+                        // Untouched so far, so it's whatever's stored
+                        None if !replaced.contains(key) => self
+                            .entries
+                            .get(key)
+                            .and_then(|x| x.value.hash())
+                            .and_then(|x| x.get(field))
+                            .cloned(),
+                        None => None,
+                    };
+                    if let Some(value) = value {
+                        value.parse::<i64>()?;
+                    }
+                    fields.insert((key, field), None);
+                }
+                _ => {}
+            }
+            kinds.insert(key, Some(kind));
+        }
+
+        Ok(())
+    }
+
+    /// Applies `ops` in order, all or nothing.
+    fn run(&mut self, ops: Vec<Op>) -> anyhow::Result<()> {
+        self.check(&ops)?;
+        for op in ops {
+            match op {
+                Op::Set(key, value, expiry) => self.set(&key, &value, expiry),
+                Op::Del(key) => {
+                    self.del(&key);
+                }
+                Op::SAdd(key, member) => {
+                    self.sadd(&key, &member)?;
+                }
+                Op::SRem(key, member) => {
+                    self.srem(&key, &member)?;
+                }
+                Op::HSet(key, fields) => self.hset(&key, fields)?,
+                Op::HDel(key, field) => {
+                    self.hdel(&key, &field)?;
+                }
+                Op::HIncrBy(key, field, by) => {
+                    self.hincrby(&key, &field, by)?;
+                }
+                Op::ZAdd(key, score, member) => self.zadd(&key, score, &member, false)?,
+                Op::ZAddNx(key, score, member) => self.zadd(&key, score, &member, true)?,
+                Op::ZRem(key, member) => {
+                    self.zrem(&key, &member)?;
+                }
+                Op::ZIncrBy(key, by, member) => {
+                    self.zincrby(&key, by, &member)?;
+                }
+                Op::PfAdd(key, member) => self.pfadd(&key, &member)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps everything in memory, optionally saving it to a JSON file so it survives restarts.
+///
+/// This is meant for small deployments and development - use Redis for anything big.
+#[derive(Clone)]
+pub struct MemoryStorage {
+    data: Arc<Mutex<Data>>,
+    path: Option<String>,
+}
+
+impl MemoryStorage {
+    /// Loads what was saved at `path`, if anything was.
+    pub fn open(path: Option<String>) -> anyhow::Result<Self> {
+        let mut data = Data::default();
+        if let Some(path) = &path {
+            match std::fs::read(path) {
+                Ok(json) => {
+                    data.entries = serde_json::from_slice(&json)?;
+                    println!("Loaded {} keys from {}", data.entries.len(), path);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(Self {
+            data: Arc::new(Mutex::new(data)),
+            path,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Data> {
+        self.data.lock().unwrap()
+    }
+
+    async fn save(&self, path: &str) -> anyhow::Result<()> {
+        let json = {
+            let mut data = self.lock();
+            if !data.dirty {
+                return Ok(());
+            }
+            data.dirty = false;
+            serde_json::to_vec(&data.entries)?
+        };
+
+        // Write to a temporary file first so a crash can't leave a half-written database
+        let tmp_path = format!("{}.tmp", path);
+        tokio::fs::write(&tmp_path, json).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+
+    /// Saves to `path` every so often, for as long as the server runs.
+    pub async fn persist(self) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+
+        let mut interval =
+            tokio::time::interval(tokio::time::Duration::from_secs(PERSIST_INTERVAL));
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = self.save(&path).await {
+                self.lock().dirty = true;
+                eprintln!("Failed to save database to {}: {}", path, e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let mut data = self.lock();
+        data.expire(key);
+        match data.entries.get(key) {
+            Some(entry) => Ok(Some(entry.value.string().ok_or_else(wrong_type)?.clone())),
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, value: &str, expiry: Option<Expiry>) -> anyhow::Result<()> {
+        self.lock().set(key, value, expiry);
+        Ok(())
+    }
+
+    async fn set_nx(&self, key: &str, value: &str, expiry: Expiry) -> anyhow::Result<bool> {
+        let mut data = self.lock();
+        data.expire(key);
+        if data.entries.contains_key(key) {
+            return Ok(false);
+        }
+
+        data.set(key, value, Some(expiry));
+        Ok(true)
+    }
+
+    async fn del(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.lock().del(key))
+    }
+
+    async fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        let mut data = self.lock();
+        data.expire(key);
+        Ok(data.entries.contains_key(key))
+    }
+
+    async fn ttl(&self, key: &str) -> anyhow::Result<i64> {
+        let mut data = self.lock();
+        data.expire(key);
+        Ok(match data.entries.get(key) {
+            None => -2,
+            Some(Entry { expires: None, .. }) => -1,
+            Some(Entry {
+                expires: Some(expires),
+                ..
+            }) => (expires - now()) / 1000,
+        })
+    }
+
+    async fn scan(&self, pattern: &str) -> anyhow::Result<Vec<String>> {
+        let data = self.lock();
+        let now = now();
+        Ok(data
+            .entries
+            .iter()
+            .filter(|(key, entry)| entry.expires.is_none_or(|x| x > now) && glob(pattern, key))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    async fn sadd(&self, key: &str, member: &str) -> anyhow::Result<bool> {
+        self.lock().sadd(key, member)
+    }
+
+    async fn srem(&self, key: &str, member: &str) -> anyhow::Result<bool> {
+        self.lock().srem(key, member)
+    }
+
+    async fn smembers(&self, key: &str) -> anyhow::Result<Vec<String>> {
+        self.lock()
+            .read(key, Value::set, |x| x.iter().cloned().collect())
+    }
+
+    async fn sismember(&self, key: &str, member: &str) -> anyhow::Result<bool> {
+        self.lock().read(key, Value::set, |x| x.contains(member))
+    }
+
+    async fn scard(&self, key: &str) -> anyhow::Result<usize> {
+        self.lock().read(key, Value::set, |x| x.len())
+    }
+
+    async fn hget(&self, key: &str, field: &str) -> anyhow::Result<Option<String>> {
+        self.lock()
+            .read(key, Value::hash, |x| x.get(field).cloned())
+    }
+
+    async fn hmget(&self, key: &str, fields: &[&str]) -> anyhow::Result<Vec<Option<String>>> {
+        self.lock().read(key, Value::hash, |x| {
+            fields.iter().map(|field| x.get(*field).cloned()).collect()
+        })
+    }
+
+    async fn hgetall(&self, key: &str) -> anyhow::Result<HashMap<String, String>> {
+        self.lock().read(key, Value::hash, |x| x.clone())
+    }
+
+    async fn hset(&self, key: &str, fields: HashMap<String, String>) -> anyhow::Result<()> {
+        self.lock().hset(key, fields)
+    }
+
+    async fn hdel(&self, key: &str, field: &str) -> anyhow::Result<bool> {
+        self.lock().hdel(key, field)
+    }
+
+    async fn zadd_nx(&self, key: &str, score: f64, member: &str) -> anyhow::Result<()> {
+        self.lock().zadd(key, score, member, true)
+    }
+
+    async fn zrem(&self, key: &str, member: &str) -> anyhow::Result<bool> {
+        self.lock().zrem(key, member)
+    }
+
+    async fn zrem_many(&self, key: &str, members: &[String]) -> anyhow::Result<usize> {
+        self.lock().write(
+            key,
+            || Value::SortedSet(SortedSet::default()),
+            Value::sorted_set_mut,
+            |x| members.iter().filter(|m| x.remove(m)).count(),
+        )
+    }
+
+    async fn zincrby(&self, key: &str, by: f64, member: &str) -> anyhow::Result<f64> {
+        self.lock().zincrby(key, by, member)
+    }
+
+    async fn zscore(&self, key: &str, member: &str) -> anyhow::Result<Option<f64>> {
+        self.lock()
+            .read(key, Value::sorted_set, |x| x.scores.get(member).copied())
+    }
+
+    async fn zmscore(&self, key: &str, members: &[String]) -> anyhow::Result<Vec<Option<f64>>> {
+        self.lock().read(key, Value::sorted_set, |x| {
+            members.iter().map(|m| x.scores.get(m).copied()).collect()
+        })
+    }
+
+    async fn zcard(&self, key: &str) -> anyhow::Result<usize> {
+        self.lock().read(key, Value::sorted_set, |x| x.scores.len())
+    }
+
+    async fn zrange(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .zrange_withscores(key, start, stop)
+            .await?
+            .into_iter()
+            .map(|(member, _)| member)
+            .collect())
+    }
+
+    async fn zrange_withscores(
+        &self,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> anyhow::Result<Vec<(String, f64)>> {
+        self.lock().read(key, Value::sorted_set, |x| {
+            let range = range(x.order.len(), start, stop);
+            x.order
+                .iter()
+                .skip(range.start)
+                .take(range.len())
+                .map(|(score, member)| (member.clone(), score.0))
+                .collect()
+        })
+    }
+
+    async fn zrevrange(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<Vec<String>> {
+        self.lock().read(key, Value::sorted_set, |x| {
+            let range = range(x.order.len(), start, stop);
+            x.order
+                .iter()
+                .rev()
+                .skip(range.start)
+                .take(range.len())
+                .map(|(_, member)| member.clone())
+                .collect()
+        })
+    }
+
+    async fn zrangebyscore(
+        &self,
+        key: &str,
+        max: f64,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<String>> {
+        self.lock().read(key, Value::sorted_set, |x| {
+            x.order
+                .iter()
+                .take_while(|(score, _)| score.0 <= max)
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|(_, member)| member.clone())
+                .collect()
+        })
+    }
+
+    async fn zrangebylex(
+        &self,
+        key: &str,
+        min: &str,
+        max: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<String>> {
+        self.lock().read(key, Value::sorted_set, |x| {
+            x.order
+                .iter()
+                .map(|(_, member)| member)
+                .filter(|member| member.as_str() >= min && member.as_str() <= max)
+                .take(limit)
+                .cloned()
+                .collect()
+        })
+    }
+
+    async fn zscan(&self, key: &str, pattern: &str) -> anyhow::Result<Vec<String>> {
+        self.lock().read(key, Value::sorted_set, |x| {
+            x.scores
+                .keys()
+                .filter(|member| glob(pattern, member))
+                .cloned()
+                .collect()
+        })
+    }
+
+    async fn pfcount(&self, key: &str) -> anyhow::Result<usize> {
+        self.lock().read(key, Value::log, |x| x.len())
+    }
+
+    async fn lpush(&self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.lock().write(
+            key,
+            || Value::List(VecDeque::new()),
+            Value::list_mut,
+            |x| x.push_front(value.to_string()),
+        )
+    }
+
+    async fn ltrim(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<()> {
+        self.lock().write(
+            key,
+            || Value::List(VecDeque::new()),
+            Value::list_mut,
+            |x| {
+                let range = range(x.len(), start, stop);
+                x.truncate(range.end);
+                x.drain(..range.start);
+            },
+        )
+    }
+
+    async fn lrange(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<Vec<String>> {
+        self.lock().read(key, Value::list, |x| {
+            let range = range(x.len(), start, stop);
+            x.range(range).cloned().collect()
+        })
+    }
+
+    async fn apply(&self, ops: Vec<Op>) -> anyhow::Result<()> {
+        self.lock().run(ops)
+    }
+
+    async fn watch(&self) -> anyhow::Result<Box<dyn Watch + '_>> {
+        Ok(Box::new(MemoryWatch {
+            storage: self,
+            versions: HashMap::new(),
+        }))
+    }
+}
+
+/// Remembers what watched keys looked like, to compare against when applying.
+struct MemoryWatch<'a> {
+    storage: &'a MemoryStorage,
+    versions: HashMap<String, Option<u64>>,
+}
+
+#[async_trait]
+impl Watch for MemoryWatch<'_> {
+    async fn watch(&mut self, keys: &[String]) -> anyhow::Result<()> {
+        let mut data = self.storage.lock();
+        for key in keys {
+            if !self.versions.contains_key(key) {
+                let version = data.version(key);
+                self.versions.insert(key.clone(), version);
+            }
+        }
+        Ok(())
+    }
+
+    async fn apply(self: Box<Self>, ops: Vec<Op>) -> anyhow::Result<bool> {
+        let mut data = self.storage.lock();
+        for (key, version) in &self.versions {
+            if data.version(key) != *version {
+                return Ok(false);
+            }
+        }
+
+        data.run(ops)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> MemoryStorage {
+        MemoryStorage::open(None).unwrap()
+    }
+
+    #[test]
+    fn range_matches_redis() {
+        // LRANGE on a five item list
+        assert_eq!(range(5, 0, -1), 0..5);
+        assert_eq!(range(5, 1, 2), 1..3);
+        assert_eq!(range(5, -2, -1), 3..5);
+        assert_eq!(range(5, -100, 100), 0..5);
+        assert_eq!(range(5, 3, 1), 0..0);
+        assert_eq!(range(5, 5, 10), 0..0);
+        assert_eq!(range(5, 0, -6), 0..0);
+        assert_eq!(range(0, 0, -1), 0..0);
+        assert_eq!(range(0, 0, 0), 0..0);
+    }
+
+    #[test]
+    fn glob_matches_redis() {
+        assert!(glob("*", ""));
+        assert!(glob("*", "anything"));
+        assert!(glob("pages:data:*", "pages:data:abc"));
+        assert!(glob("pages:data:*", "pages:data:"));
+        assert!(!glob("pages:data:*", "pages:linksto:abc"));
+        assert!(glob("*.com*", "example.com"));
+        assert!(glob("link:*:*", "link:a:b"));
+        assert!(!glob("link:*:*", "link:ab"));
+        assert!(glob("a*a", "aa"));
+        assert!(!glob("a*a", "a"));
+        assert!(glob("exact", "exact"));
+        assert!(!glob("exact", "exactly"));
+    }
+
+    #[tokio::test]
+    async fn zrangebylex_is_inclusive() {
+        let db = storage();
+        for member in ["a.com", "ab.com", "b.com", "example.com", "example.org"] {
+            db.zadd_nx("search:domains", 0.0, member).await.unwrap();
+        }
+
+        // The same bounds search uses for prefix matches
+        let found = db
+            .zrangebylex("search:domains", "example", "example\x7f", 10)
+            .await
+            .unwrap();
+        assert_eq!(found, vec!["example.com", "example.org"]);
+
+        let found = db
+            .zrangebylex("search:domains", "a.com", "b.com", 10)
+            .await
+            .unwrap();
+        assert_eq!(found, vec!["a.com", "ab.com", "b.com"]);
+
+        let found = db
+            .zrangebylex("search:domains", "a", "a\x7f", 1)
+            .await
+            .unwrap();
+        assert_eq!(found, vec!["a.com"]);
+
+        let found = db
+            .zrangebylex("search:domains", "a", "a\x7f", 0)
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sorted_sets_order_by_score_then_member() {
+        let db = storage();
+        db.apply(vec![
+            Op::ZAdd("z".to_string(), 2.0, "b".to_string()),
+            Op::ZAdd("z".to_string(), 1.0, "c".to_string()),
+            Op::ZAdd("z".to_string(), 2.0, "a".to_string()),
+            Op::ZAddNx("z".to_string(), 5.0, "c".to_string()),
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(db.zrange("z", 0, -1).await.unwrap(), vec!["c", "a", "b"]);
+        assert_eq!(db.zrevrange("z", 0, 0).await.unwrap(), vec!["b"]);
+        assert_eq!(db.zrangebyscore("z", 1.0, None).await.unwrap(), vec!["c"]);
+        assert_eq!(db.zincrby("z", 2.0, "c").await.unwrap(), 3.0);
+        assert_eq!(db.zrange("z", -1, -1).await.unwrap(), vec!["c"]);
+    }
+
+    #[tokio::test]
+    async fn empty_collections_are_deleted() {
+        let db = storage();
+        db.sadd("s", "a").await.unwrap();
+        db.hset(
+            "h",
+            HashMap::from_iter(vec![("f".to_string(), "1".to_string())]),
+        )
+        .await
+        .unwrap();
+        db.zadd_nx("z", 0.0, "a").await.unwrap();
+        db.lpush("l", "a").await.unwrap();
+
+        db.srem("s", "a").await.unwrap();
+        db.hdel("h", "f").await.unwrap();
+        db.zrem("z", "a").await.unwrap();
+        db.ltrim("l", 1, 0).await.unwrap();
+        for key in ["s", "h", "z", "l"] {
+            assert!(!db.exists(key).await.unwrap(), "{} still exists", key);
+        }
+
+        // Removing from a key that doesn't exist doesn't create it either
+        assert!(!db.srem("missing", "a").await.unwrap());
+        assert!(!db.exists("missing").await.unwrap());
+        assert!(db.scan("*").await.unwrap().is_empty());
+
+        // Nor does setting no fields at all
+        db.hset("h", HashMap::new()).await.unwrap();
+        assert!(!db.exists("h").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn wrong_types_are_errors() {
+        let db = storage();
+        db.set("key", "value", None).await.unwrap();
+        assert!(db.sadd("key", "a").await.is_err());
+        assert!(db.hgetall("key").await.is_err());
+        assert!(db.zscore("key", "a").await.is_err());
+
+        db.sadd("set", "a").await.unwrap();
+        assert!(db.get("set").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_gone() {
+        let db = storage();
+        db.set("gone", "1", Some(Expiry::Millis(-1))).await.unwrap();
+        db.set("kept", "1", Some(Expiry::Seconds(60)))
+            .await
+            .unwrap();
+
+        assert_eq!(db.get("gone").await.unwrap(), None);
+        assert_eq!(db.ttl("gone").await.unwrap(), -2);
+        assert!(db.set_nx("gone", "2", Expiry::Seconds(60)).await.unwrap());
+        assert!(!db.set_nx("kept", "2", Expiry::Seconds(60)).await.unwrap());
+        assert!((58..=60).contains(&db.ttl("kept").await.unwrap()));
+
+        db.set("forever", "1", None).await.unwrap();
+        assert_eq!(db.ttl("forever").await.unwrap(), -1);
+    }
+
+    #[tokio::test]
+    async fn apply_runs_ops_in_order() {
+        let db = storage();
+        db.hset(
+            "h",
+            HashMap::from_iter(vec![("old".to_string(), "1".to_string())]),
+        )
+        .await
+        .unwrap();
+
+        db.apply(vec![
+            Op::Del("h".to_string()),
+            Op::HSet(
+                "h".to_string(),
+                HashMap::from_iter(vec![("new".to_string(), "1".to_string())]),
+            ),
+            Op::HIncrBy("h".to_string(), "new".to_string(), 2),
+            Op::HIncrBy("h".to_string(), "count".to_string(), -1),
+            Op::SAdd("s".to_string(), "a".to_string()),
+            Op::SRem("s".to_string(), "a".to_string()),
+            Op::PfAdd("log".to_string(), "a".to_string()),
+            Op::PfAdd("log".to_string(), "a".to_string()),
+            Op::Set("string".to_string(), "1".to_string(), None),
+        ])
+        .await
+        .unwrap();
+
+        let h = db.hgetall("h").await.unwrap();
+        assert_eq!(h.get("old"), None);
+        assert_eq!(h.get("new").map(String::as_str), Some("3"));
+        assert_eq!(h.get("count").map(String::as_str), Some("-1"));
+        assert!(!db.exists("s").await.unwrap());
+        assert_eq!(db.pfcount("log").await.unwrap(), 1);
+        assert_eq!(db.get("string").await.unwrap().as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn apply_changes_nothing_when_an_op_fails() {
+        let db = storage();
+        db.set("string", "1", None).await.unwrap();
+        db.hset(
+            "h",
+            HashMap::from_iter(vec![("text".to_string(), "a".to_string())]),
+        )
+        .await
+        .unwrap();
+
+        let wrong_type = db
+            .apply(vec![
+                Op::SAdd("s".to_string(), "a".to_string()),
+                Op::SAdd("string".to_string(), "a".to_string()),
+            ])
+            .await;
+        assert!(wrong_type.is_err());
+        assert!(!db.exists("s").await.unwrap());
+
+        let not_a_number = db
+            .apply(vec![
+                Op::HIncrBy("h".to_string(), "count".to_string(), 1),
+                Op::HIncrBy("h".to_string(), "text".to_string(), 1),
+            ])
+            .await;
+        assert!(not_a_number.is_err());
+        assert_eq!(db.hget("h", "count").await.unwrap(), None);
+
+        // Earlier ops in the batch decide what later ones find
+        db.apply(vec![
+            Op::Del("string".to_string()),
+            Op::SAdd("string".to_string(), "a".to_string()),
+            Op::HSet(
+                "h".to_string(),
+                HashMap::from_iter(vec![("text".to_string(), "1".to_string())]),
+            ),
+            Op::HIncrBy("h".to_string(), "text".to_string(), 1),
+        ])
+        .await
+        .unwrap();
+        assert!(db.sismember("string", "a").await.unwrap());
+        assert_eq!(db.hget("h", "text").await.unwrap().as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn watch_skips_writes_after_a_change() {
+        let db = storage();
+        db.set("a", "1", None).await.unwrap();
+
+        let mut watch = db.watch().await.unwrap();
+        watch.watch(&["a".to_string()]).await.unwrap();
+        let applied = watch
+            .apply(vec![Op::Set("b".to_string(), "1".to_string(), None)])
+            .await
+            .unwrap();
+        assert!(applied);
+
+        let mut watch = db.watch().await.unwrap();
+        watch
+            .watch(&["a".to_string(), "missing".to_string()])
+            .await
+            .unwrap();
+        db.sadd("missing", "x").await.unwrap();
+        let applied = watch
+            .apply(vec![Op::Set("b".to_string(), "2".to_string(), None)])
+            .await
+            .unwrap();
+        assert!(!applied);
+        assert_eq!(db.get("b").await.unwrap().as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn lists_push_to_the_front_and_trim() {
+        let db = storage();
+        for value in ["a", "b", "c", "d"] {
+            db.lpush("l", value).await.unwrap();
+        }
+        db.ltrim("l", 0, 2).await.unwrap();
+        assert_eq!(db.lrange("l", 0, -1).await.unwrap(), vec!["d", "c", "b"]);
+        assert_eq!(db.lrange("l", -1, -1).await.unwrap(), vec!["b"]);
+    }
+}
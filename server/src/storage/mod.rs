@@ -0,0 +1,149 @@
+use crate::Config;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc};
+
+mod memory;
+mod redis;
+
+pub use self::memory::MemoryStorage;
+pub use self::redis::RedisStorage;
+
+/// Which database the crawl state lives in.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Redis,
+    /// Kept in memory, and saved to `storage_path` if one is set
+    Memory,
+}
+
+/// How long until a key is deleted.
+#[derive(Debug, Clone, Copy)]
+pub enum Expiry {
+    Seconds(i64),
+    Millis(i64),
+}
+
+/// A write that can be applied alongside others with [`Storage::apply`].
+#[derive(Debug, Clone)]
+pub enum Op {
+    Set(String, String, Option<Expiry>),
+    Del(String),
+    SAdd(String, String),
+    SRem(String, String),
+    HSet(String, HashMap<String, String>),
+    HDel(String, String),
+    HIncrBy(String, String, i64),
+    ZAdd(String, f64, String),
+    ZAddNx(String, f64, String),
+    ZRem(String, String),
+    ZIncrBy(String, f64, String),
+    PfAdd(String, String),
+}
+
+/// The operations the server needs from its database.
+///
+/// These follow Redis' data types and semantics, so anything stored by older
+/// versions keeps working with the Redis backend. Implementations are shared
+/// between every handler and must cope with being called concurrently.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>>;
+    async fn set(&self, key: &str, value: &str, expiry: Option<Expiry>) -> anyhow::Result<()>;
+    /// Sets `key` only if it doesn't exist yet, returning whether it was set.
+    async fn set_nx(&self, key: &str, value: &str, expiry: Expiry) -> anyhow::Result<bool>;
+    async fn del(&self, key: &str) -> anyhow::Result<bool>;
+    async fn exists(&self, key: &str) -> anyhow::Result<bool>;
+    /// Seconds until `key` expires, or negative if it doesn't exist or never expires.
+    async fn ttl(&self, key: &str) -> anyhow::Result<i64>;
+    /// Every key matching `pattern`, where `*` matches anything.
+    async fn scan(&self, pattern: &str) -> anyhow::Result<Vec<String>>;
+
+    async fn sadd(&self, key: &str, member: &str) -> anyhow::Result<bool>;
+    async fn srem(&self, key: &str, member: &str) -> anyhow::Result<bool>;
+    async fn smembers(&self, key: &str) -> anyhow::Result<Vec<String>>;
+    async fn sismember(&self, key: &str, member: &str) -> anyhow::Result<bool>;
+    async fn scard(&self, key: &str) -> anyhow::Result<usize>;
+
+    async fn hget(&self, key: &str, field: &str) -> anyhow::Result<Option<String>>;
+    async fn hmget(&self, key: &str, fields: &[&str]) -> anyhow::Result<Vec<Option<String>>>;
+    async fn hgetall(&self, key: &str) -> anyhow::Result<HashMap<String, String>>;
+    async fn hset(&self, key: &str, fields: HashMap<String, String>) -> anyhow::Result<()>;
+    async fn hdel(&self, key: &str, field: &str) -> anyhow::Result<bool>;
+
+    /// Adds `member` only if it isn't in the set yet, leaving existing scores alone.
+    async fn zadd_nx(&self, key: &str, score: f64, member: &str) -> anyhow::Result<()>;
+    async fn zrem(&self, key: &str, member: &str) -> anyhow::Result<bool>;
+    async fn zrem_many(&self, key: &str, members: &[String]) -> anyhow::Result<usize>;
+    async fn zincrby(&self, key: &str, by: f64, member: &str) -> anyhow::Result<f64>;
+    async fn zscore(&self, key: &str, member: &str) -> anyhow::Result<Option<f64>>;
+    async fn zmscore(&self, key: &str, members: &[String]) -> anyhow::Result<Vec<Option<f64>>>;
+    async fn zcard(&self, key: &str) -> anyhow::Result<usize>;
+    /// Members by rank, lowest score first. Negative ranks count from the end.
+    async fn zrange(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<Vec<String>>;
+    async fn zrange_withscores(
+        &self,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> anyhow::Result<Vec<(String, f64)>>;
+    /// Members by rank, highest score first.
+    async fn zrevrange(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<Vec<String>>;
+    /// Members scored at most `max`, lowest first.
+    async fn zrangebyscore(
+        &self,
+        key: &str,
+        max: f64,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<String>>;
+    /// Members between `min` and `max` inclusive, for sets where every score is the same.
+    async fn zrangebylex(
+        &self,
+        key: &str,
+        min: &str,
+        max: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<String>>;
+    /// Every member matching `pattern`, where `*` matches anything.
+    async fn zscan(&self, key: &str, pattern: &str) -> anyhow::Result<Vec<String>>;
+
+    async fn pfcount(&self, key: &str) -> anyhow::Result<usize>;
+
+    async fn lpush(&self, key: &str, value: &str) -> anyhow::Result<()>;
+    async fn ltrim(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<()>;
+    async fn lrange(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<Vec<String>>;
+
+    /// Applies every write at once, so nothing sees only some of them.
+    async fn apply(&self, ops: Vec<Op>) -> anyhow::Result<()>;
+    /// Starts a write that only goes through if nothing it depends on changes first.
+    async fn watch(&self) -> anyhow::Result<Box<dyn Watch + '_>>;
+}
+
+/// Writes depending on keys that were read beforehand, like Redis' `WATCH`.
+#[async_trait]
+pub trait Watch: Send {
+    /// Adds `keys` to what's watched. Keys must be watched before they're read.
+    async fn watch(&mut self, keys: &[String]) -> anyhow::Result<()>;
+    /// Applies `ops` like [`Storage::apply`], unless a watched key has been written to
+    /// since. Returns whether they were applied, so the caller can read again and retry.
+    async fn apply(self: Box<Self>, ops: Vec<Op>) -> anyhow::Result<bool>;
+}
+
+/// Opens whichever database the config asks for.
+pub async fn open(config: &Config) -> anyhow::Result<Arc<dyn Storage>> {
+    match config.storage.unwrap_or_default() {
+        Backend::Redis => {
+            let storage = RedisStorage::connect(config).await?;
+            Ok(Arc::new(storage))
+        }
+        Backend::Memory => {
+            let storage = MemoryStorage::open(config.storage_path.clone())?;
+            if config.storage_path.is_some() {
+                tokio::spawn(storage.clone().persist());
+            }
+            Ok(Arc::new(storage))
+        }
+    }
+}
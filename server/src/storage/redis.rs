@@ -0,0 +1,434 @@
+use super::{Expiry, Op, Storage, Watch};
+use crate::Config;
+use async_trait::async_trait;
+use fred::{
+    clients::{RedisClient, RedisPool},
+    interfaces::{
+        ClientLike, HashesInterface, HyperloglogInterface, KeysInterface, ListInterface,
+        SetsInterface, SortedSetsInterface, TransactionInterface,
+    },
+    types::{Expiration, RedisConfig, RedisValue, Scanner, Server, ServerConfig, SetOptions},
+};
+use futures::StreamExt;
+use std::{collections::HashMap, sync::Mutex};
+
+/// How many connections to Redis are kept open
+pub const DEFAULT_POOL_SIZE: usize = 8;
+
+pub struct RedisStorage {
+    client: RedisPool,
+    /// Connections for [`RedisStorage::watch`], which can't share one with other commands
+    watchers: Mutex<Vec<RedisClient>>,
+    size: usize,
+}
+
+impl RedisStorage {
+    pub async fn connect(config: &Config) -> anyhow::Result<Self> {
+        let mut redis_config = RedisConfig::default();
+        if let Some(host) = &config.redis_host {
+            let port = config.redis_port.unwrap_or(6379);
+            redis_config.server = ServerConfig::Centralized {
+                server: Server {
+                    host: host.clone().into(),
+                    port,
+                },
+            };
+        }
+
+        let size = config.redis_pool_size.unwrap_or(DEFAULT_POOL_SIZE).max(1);
+        let client = RedisPool::new(redis_config, None, None, None, size)?;
+        client.connect();
+        client.wait_for_connect().await?;
+        Ok(Self {
+            client,
+            watchers: Mutex::new(Vec::new()),
+            size,
+        })
+    }
+}
+
+fn expiration(expiry: Expiry) -> Expiration {
+    match expiry {
+        Expiry::Seconds(x) => Expiration::EX(x),
+        Expiry::Millis(x) => Expiration::PX(x),
+    }
+}
+
+/// Sends a single write, either straight away or queued up in a transaction.
+async fn run<C>(client: &C, op: Op) -> anyhow::Result<()>
+where
+    C: KeysInterface
+        + SetsInterface
+        + HashesInterface
+        + SortedSetsInterface
+        + HyperloglogInterface
+        + Sync,
+{
+    match op {
+        Op::Set(key, value, expiry) => {
+            client
+                .set::<(), _, _>(key, value, expiry.map(expiration), None, false)
+                .await?
+        }
+        Op::Del(key) => client.del::<(), _>(key).await?,
+        Op::SAdd(key, member) => client.sadd::<(), _, _>(key, member).await?,
+        Op::SRem(key, member) => client.srem::<(), _, _>(key, member).await?,
+        // HSET with no fields is an error
+        Op::HSet(_, fields) if fields.is_empty() => {}
+        Op::HSet(key, fields) => client.hset::<(), _, _>(key, fields).await?,
+        Op::HDel(key, field) => client.hdel::<(), _, _>(key, field).await?,
+        Op::HIncrBy(key, field, by) => client.hincrby::<(), _, _>(key, field, by).await?,
+        Op::ZAdd(key, score, member) => {
+            client
+                .zadd::<(), _, _>(key, None, None, false, false, (score, member))
+                .await?
+        }
+        Op::ZAddNx(key, score, member) => {
+            client
+                .zadd::<(), _, _>(
+                    key,
+                    Some(SetOptions::NX),
+                    None,
+                    false,
+                    false,
+                    (score, member),
+                )
+                .await?
+        }
+        Op::ZRem(key, member) => client.zrem::<(), _, _>(key, member).await?,
+        Op::ZIncrBy(key, by, member) => client.zincrby::<(), _, _>(key, by, member).await?,
+        Op::PfAdd(key, member) => client.pfadd::<(), _, _>(key, member).await?,
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.client.get(key).await?)
+    }
+
+    async fn set(&self, key: &str, value: &str, expiry: Option<Expiry>) -> anyhow::Result<()> {
+        self.client
+            .set::<(), _, _>(key, value, expiry.map(expiration), None, false)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_nx(&self, key: &str, value: &str, expiry: Expiry) -> anyhow::Result<bool> {
+        let set: Option<String> = self
+            .client
+            .set(
+                key,
+                value,
+                Some(expiration(expiry)),
+                Some(SetOptions::NX),
+                false,
+            )
+            .await?;
+        Ok(set.is_some())
+    }
+
+    async fn del(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.client.del::<usize, _>(key).await? > 0)
+    }
+
+    async fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.client.exists(key).await?)
+    }
+
+    async fn ttl(&self, key: &str) -> anyhow::Result<i64> {
+        Ok(self.client.ttl(key).await?)
+    }
+
+    async fn scan(&self, pattern: &str) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut scan = self.client.next().scan(pattern, Some(1000), None);
+        while let Some(mut page) = scan.next().await.transpose()? {
+            for key in page.take_results().unwrap_or_default() {
+                keys.extend(key.into_string());
+            }
+
+            page.next()?;
+        }
+
+        Ok(keys)
+    }
+
+    async fn sadd(&self, key: &str, member: &str) -> anyhow::Result<bool> {
+        Ok(self.client.sadd::<usize, _, _>(key, member).await? > 0)
+    }
+
+    async fn srem(&self, key: &str, member: &str) -> anyhow::Result<bool> {
+        Ok(self.client.srem::<usize, _, _>(key, member).await? > 0)
+    }
+
+    async fn smembers(&self, key: &str) -> anyhow::Result<Vec<String>> {
+        Ok(self.client.smembers(key).await?)
+    }
+
+    async fn sismember(&self, key: &str, member: &str) -> anyhow::Result<bool> {
+        Ok(self.client.sismember(key, member).await?)
+    }
+
+    async fn scard(&self, key: &str) -> anyhow::Result<usize> {
+        Ok(self.client.scard(key).await?)
+    }
+
+    async fn hget(&self, key: &str, field: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.client.hget(key, field).await?)
+    }
+
+    async fn hmget(&self, key: &str, fields: &[&str]) -> anyhow::Result<Vec<Option<String>>> {
+        Ok(self.client.hmget(key, fields.to_vec()).await?)
+    }
+
+    async fn hgetall(&self, key: &str) -> anyhow::Result<HashMap<String, String>> {
+        Ok(self.client.hgetall(key).await?)
+    }
+
+    async fn hset(&self, key: &str, fields: HashMap<String, String>) -> anyhow::Result<()> {
+        if fields.is_empty() {
+            return Ok(());
+        }
+
+        self.client.hset::<(), _, _>(key, fields).await?;
+        Ok(())
+    }
+
+    async fn hdel(&self, key: &str, field: &str) -> anyhow::Result<bool> {
+        Ok(self.client.hdel::<usize, _, _>(key, field).await? > 0)
+    }
+
+    async fn zadd_nx(&self, key: &str, score: f64, member: &str) -> anyhow::Result<()> {
+        self.client
+            .zadd::<(), _, _>(
+                key,
+                Some(SetOptions::NX),
+                None,
+                false,
+                false,
+                (score, member),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn zrem(&self, key: &str, member: &str) -> anyhow::Result<bool> {
+        Ok(self.client.zrem::<usize, _, _>(key, member).await? > 0)
+    }
+
+    async fn zrem_many(&self, key: &str, members: &[String]) -> anyhow::Result<usize> {
+        if members.is_empty() {
+            return Ok(0);
+        }
+
+        Ok(self.client.zrem(key, members.to_vec()).await?)
+    }
+
+    async fn zincrby(&self, key: &str, by: f64, member: &str) -> anyhow::Result<f64> {
+        Ok(self.client.zincrby(key, by, member).await?)
+    }
+
+    async fn zscore(&self, key: &str, member: &str) -> anyhow::Result<Option<f64>> {
+        Ok(self.client.zscore(key, member).await?)
+    }
+
+    async fn zmscore(&self, key: &str, members: &[String]) -> anyhow::Result<Vec<Option<f64>>> {
+        if members.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self.client.zmscore(key, members.to_vec()).await?)
+    }
+
+    async fn zcard(&self, key: &str) -> anyhow::Result<usize> {
+        Ok(self.client.zcard(key).await?)
+    }
+
+    async fn zrange(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .client
+            .zrange(key, start, stop, None, false, None, false)
+            .await?)
+    }
+
+    async fn zrange_withscores(
+        &self,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> anyhow::Result<Vec<(String, f64)>> {
+        let range: Vec<String> = self
+            .client
+            .zrange(key, start, stop, None, false, None, true)
+            .await?;
+
+        // WITHSCORES returns results as [item0, score0, item1, score1, ...]
+        let mut members = Vec::new();
+        for chunk in range.chunks_exact(2) {
+            members.push((chunk[0].clone(), chunk[1].parse()?));
+        }
+        Ok(members)
+    }
+
+    async fn zrevrange(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<Vec<String>> {
+        Ok(self.client.zrevrange(key, start, stop, false).await?)
+    }
+
+    async fn zrangebyscore(
+        &self,
+        key: &str,
+        max: f64,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .client
+            .zrangebyscore(key, "-inf", max, false, limit.map(|x| (0, x as i64)))
+            .await?)
+    }
+
+    async fn zrangebylex(
+        &self,
+        key: &str,
+        min: &str,
+        max: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .client
+            .zrangebylex(
+                key,
+                format!("[{}", min),
+                format!("[{}", max),
+                Some((0, limit as i64)),
+            )
+            .await?)
+    }
+
+    async fn zscan(&self, key: &str, pattern: &str) -> anyhow::Result<Vec<String>> {
+        let mut members = Vec::new();
+        let mut scan = Box::pin(self.client.next().zscan(key, pattern, Some(1000)));
+        while let Some(mut page) = scan.next().await.transpose()? {
+            for (member, _) in page.take_results().unwrap_or_default() {
+                members.extend(member.into_string());
+            }
+
+            page.next()?;
+        }
+
+        Ok(members)
+    }
+
+    async fn pfcount(&self, key: &str) -> anyhow::Result<usize> {
+        Ok(self.client.pfcount(key).await?)
+    }
+
+    async fn lpush(&self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.client.lpush::<(), _, _>(key, value).await?;
+        Ok(())
+    }
+
+    async fn ltrim(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<()> {
+        self.client.ltrim::<(), _>(key, start, stop).await?;
+        Ok(())
+    }
+
+    async fn lrange(&self, key: &str, start: i64, stop: i64) -> anyhow::Result<Vec<String>> {
+        Ok(self.client.lrange(key, start, stop).await?)
+    }
+
+    async fn apply(&self, mut ops: Vec<Op>) -> anyhow::Result<()> {
+        // A single command is already atomic, so skip the MULTI/EXEC round trips
+        if ops.len() <= 1 {
+            return match ops.pop() {
+                Some(op) => run(&self.client, op).await,
+                None => Ok(()),
+            };
+        }
+
+        let transaction = self.client.next().multi();
+        for op in ops {
+            run(&transaction, op).await?;
+        }
+
+        transaction.exec::<()>(true).await?;
+        Ok(())
+    }
+
+    async fn watch(&self) -> anyhow::Result<Box<dyn Watch + '_>> {
+        let idle = self.watchers.lock().unwrap().pop();
+        let client = match idle {
+            Some(client) => {
+                // Anything left watched by a write that was given up on
+                client.unwatch().await?;
+                client
+            }
+            None => {
+                let client = self.client.next().clone_new();
+                client.connect();
+                client.wait_for_connect().await?;
+                client
+            }
+        };
+
+        Ok(Box::new(RedisWatch {
+            storage: self,
+            client: Some(client),
+        }))
+    }
+}
+
+/// A connection with `WATCH`ed keys, handed back to the storage once done with.
+struct RedisWatch<'a> {
+    storage: &'a RedisStorage,
+    client: Option<RedisClient>,
+}
+
+impl RedisWatch<'_> {
+    fn client(&self) -> &RedisClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl Drop for RedisWatch<'_> {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+
+        let mut watchers = self.storage.watchers.lock().unwrap();
+        if watchers.len() < self.storage.size {
+            watchers.push(client);
+        } else {
+            tokio::spawn(async move {
+                let _ = client.quit().await;
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Watch for RedisWatch<'_> {
+    async fn watch(&mut self, keys: &[String]) -> anyhow::Result<()> {
+        if !keys.is_empty() {
+            self.client().watch(keys.to_vec()).await?;
+        }
+        Ok(())
+    }
+
+    async fn apply(self: Box<Self>, ops: Vec<Op>) -> anyhow::Result<bool> {
+        let transaction = self.client().multi();
+        for op in ops {
+            run(&transaction, op).await?;
+        }
+        if transaction.len() == 0 {
+            return Ok(true);
+        }
+
+        // EXEC replies with nil when a watched key changed
+        let result = transaction.exec::<RedisValue>(true).await?;
+        Ok(!result.is_null())
+    }
+}
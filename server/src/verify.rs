@@ -0,0 +1,346 @@
+use crate::{
+    commit_work, denylist, get_domain, keys, leases, politeness, queue,
+    storage::{Op, Storage},
+    AppResult, AppState, WorkSchema,
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_auth::AuthBearer;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Fraction of results from trusted keys that get checked anyway
+const DEFAULT_SAMPLE_RATE: f64 = 0.05;
+
+/// How many verified results a key needs before it's trusted
+const DEFAULT_TRUST_THRESHOLD: u64 = 50;
+
+/// How many held results to look at when handing out verification work
+const VERIFY_WINDOW: i64 = 100;
+
+/// How many disagreements to keep around for admins to look at
+const MAX_DISAGREEMENTS: i64 = 1000;
+
+/// Settings for cross-verification. Leaving this out of the config turns it off.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Verification {
+    pub sample_rate: Option<f64>,
+    pub trust_threshold: Option<u64>,
+}
+
+/// A result that's waiting on a second scraper, stored at `verify:pending:{page}`.
+#[derive(Serialize, Deserialize, Debug)]
+struct Pending {
+    submitter: String,
+    submitted: i64,
+    work: WorkSchema,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    pub result_url: String,
+    pub success: bool,
+    pub disallowed: bool,
+    pub links: BTreeSet<String>,
+}
+
+impl Summary {
+    fn new(work: &WorkSchema) -> Self {
+        Self {
+            result_url: work.result_url.clone(),
+            success: work.success,
+            disallowed: work.disallowed == Some(true),
+            links: work.links.iter().flatten().map(|x| x.to.clone()).collect(),
+        }
+    }
+}
+
+/// Two scrapers that saw different things on the same page, kept in `verify:disagreements`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Disagreement {
+    pub url: String,
+    pub time: i64,
+    pub submitter: String,
+    pub verifier: String,
+    pub submitted: Summary,
+    pub verified: Summary,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationStatus {
+    pub enabled: bool,
+    /// Held results that no scraper has picked up to verify yet
+    pub awaiting_verifier: usize,
+    pub disagreements: Vec<Disagreement>,
+}
+
+async fn pending(db: &dyn Storage, page: &str) -> anyhow::Result<Option<Pending>> {
+    let pending = db.get(&format!("verify:pending:{}", page)).await?;
+    Ok(pending.and_then(|x| serde_json::from_str(&x).ok()))
+}
+
+async fn await_verifier(db: &dyn Storage, page: &str, submitted: i64) -> anyhow::Result<()> {
+    db.zadd_nx("verify:queue", submitted as f64, page).await
+}
+
+/// Whether either end of a held result is on a denylisted domain.
+async fn denylisted(state: &AppState, db: &dyn Storage, work: &WorkSchema) -> anyhow::Result<bool> {
+    for url in [&work.orig_url, &work.result_url] {
+        if let Some(domain) = get_domain(url) {
+            if denylist::is_denylisted(db, state, &domain).await? {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+fn drop_ops(page: &str) -> Vec<Op> {
+    vec![
+        Op::Del(format!("verify:pending:{}", page)),
+        Op::ZRem("verify:queue".to_string(), page.to_string()),
+    ]
+}
+
+/// Forgets every held result with either end on a domain that `matches`, so it
+/// can't be committed later.
+pub async fn drop_where(db: &dyn Storage, matches: impl Fn(&str) -> bool) -> anyhow::Result<usize> {
+    let mut count = 0;
+    for key in db.scan("verify:pending:*").await? {
+        let page = key.trim_start_matches("verify:pending:");
+        let Some(pending) = pending(db, page).await? else {
+            continue;
+        };
+
+        let work = &pending.work;
+        let matched = [&work.orig_url, &work.result_url]
+            .into_iter()
+            .any(|url| get_domain(url).is_some_and(|x| matches(&x)));
+        if matched {
+            db.apply(drop_ops(page)).await?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Hands out a held result for a different scraper to check, oldest first.
+pub async fn claim(
+    state: &AppState,
+    db: &dyn Storage,
+    api_key_hash: &str,
+) -> anyhow::Result<Option<String>> {
+    if state.config.verification.is_none() {
+        return Ok(None);
+    }
+
+    let held = db.zrange("verify:queue", 0, VERIFY_WINDOW - 1).await?;
+    for page in held {
+        let Some(pending) = pending(db, &page).await? else {
+            db.zrem("verify:queue", &page).await?;
+            continue;
+        };
+        if pending.submitter == api_key_hash {
+            continue;
+        }
+
+        if denylisted(state, db, &pending.work).await? {
+            db.apply(drop_ops(&page)).await?;
+            continue;
+        }
+
+        let domain = get_domain(&pending.work.orig_url);
+        if let Some(domain) = &domain {
+            if politeness::cooldown(db, state, domain).await?.is_some() {
+                continue;
+            }
+        }
+
+        if !db.zrem("verify:queue", &page).await? {
+            continue;
+        }
+
+        // Another claim may have started the domain's cooldown since it was checked
+        if let Some(domain) = domain {
+            if !politeness::try_acquire(db, state, &domain).await? {
+                await_verifier(db, &page, pending.submitted).await?;
+                continue;
+            }
+        }
+
+        leases::grant(db, state, api_key_hash, &page).await?;
+        return Ok(Some(pending.work.orig_url));
+    }
+
+    Ok(None)
+}
+
+/// Decides whether a result should be held for verification, or settles a held
+/// one if this result is the second opinion on it.
+///
+/// Returns `None` when the result should be committed as usual. A held result
+/// is settled together with `release`, which is left empty.
+pub async fn intercept(
+    state: &AppState,
+    db: &dyn Storage,
+    api_key_hash: &str,
+    work: &WorkSchema,
+    release: &mut Vec<Op>,
+) -> anyhow::Result<Option<StatusCode>> {
+    let Some(config) = &state.config.verification else {
+        return Ok(None);
+    };
+    if api_key_hash == keys::hash(state, &state.config.admin_key) {
+        return Ok(None);
+    }
+
+    let page = state.base64.encode(work.orig_url.as_bytes());
+    if let Some(pending) = pending(db, &page).await? {
+        if pending.submitter == api_key_hash {
+            // A scraper can't vouch for itself, so keep waiting for another one
+            await_verifier(db, &page, pending.submitted).await?;
+        } else if !leases::holds(db, api_key_hash, &page).await? {
+            // Only a scraper the page was handed out to gets a say
+            return Ok(Some(StatusCode::CONFLICT));
+        } else {
+            let release = std::mem::take(release);
+            resolve(state, db, api_key_hash, &page, pending, work, release).await?;
+        }
+
+        return Ok(Some(StatusCode::NO_CONTENT));
+    }
+
+    let trust = db.zscore("verify:trust", api_key_hash).await?;
+    let trusted =
+        trust.unwrap_or(0.0) as u64 >= config.trust_threshold.unwrap_or(DEFAULT_TRUST_THRESHOLD);
+    let sampled = rand::random::<f64>() < config.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+    if trusted && !sampled {
+        return Ok(None);
+    }
+
+    let pending = Pending {
+        submitter: api_key_hash.to_string(),
+        submitted: chrono::Utc::now().timestamp(),
+        work: work.clone(),
+    };
+    db.set(
+        &format!("verify:pending:{}", page),
+        &serde_json::to_string(&pending)?,
+        None,
+    )
+    .await?;
+    await_verifier(db, &page, pending.submitted).await?;
+
+    Ok(Some(StatusCode::NO_CONTENT))
+}
+
+/// Compares a held result with a second scraper's, committing it if they agree.
+///
+/// The held result is dropped in the same write as the outcome, along with
+/// `release` (the verifier's lease), so a second verifier racing this one can't
+/// settle it again.
+async fn resolve(
+    state: &AppState,
+    db: &dyn Storage,
+    api_key_hash: &str,
+    page: &str,
+    pending: Pending,
+    work: &WorkSchema,
+    release: Vec<Op>,
+) -> anyhow::Result<()> {
+    let pending_key = format!("verify:pending:{}", page);
+    let mut ops = release.clone();
+    ops.extend(drop_ops(page));
+
+    let submitted = Summary::new(&pending.work);
+    let verified = Summary::new(work);
+    if submitted.result_url == verified.result_url
+        && submitted.success == verified.success
+        && submitted.disallowed == verified.disallowed
+        && submitted.links == verified.links
+    {
+        for key in [pending.submitter.as_str(), api_key_hash] {
+            ops.push(Op::ZIncrBy(
+                "verify:trust".to_string(),
+                1.0,
+                key.to_string(),
+            ));
+        }
+
+        // The verifier did the same work, so it gets the same credit
+        ops.push(Op::ZIncrBy(
+            "scraper:leaderboard".to_string(),
+            1.0,
+            api_key_hash.to_string(),
+        ));
+
+        // Another verifier may have got here first, but the lease still goes either way
+        let submitter = pending.submitter;
+        if commit_work(state, db, &submitter, pending.work, ops, Some(&pending_key)).await? {
+            println!("Verified {}", work.orig_url);
+        } else {
+            db.apply(release).await?;
+        }
+        return Ok(());
+    }
+
+    // Neither result can be trusted, so start over with a fresh scrape
+    ops.push(queue::enqueue_op(db, page, 0).await?);
+    let mut watch = db.watch().await?;
+    watch.watch(std::slice::from_ref(&pending_key)).await?;
+    if !db.exists(&pending_key).await? || !watch.apply(ops).await? {
+        return db.apply(release).await;
+    }
+
+    eprintln!("Scrapers disagree on {}", work.orig_url);
+    let disagreement = Disagreement {
+        url: work.orig_url.clone(),
+        time: chrono::Utc::now().timestamp(),
+        submitter: pending.submitter,
+        verifier: api_key_hash.to_string(),
+        submitted,
+        verified,
+    };
+    db.lpush(
+        "verify:disagreements",
+        &serde_json::to_string(&disagreement)?,
+    )
+    .await?;
+    db.ltrim("verify:disagreements", 0, MAX_DISAGREEMENTS - 1)
+        .await
+}
+
+pub async fn verification(
+    AuthBearer(token): AuthBearer,
+    State(state): State<AppState>,
+) -> AppResult<Response<Body>> {
+    if token != state.config.admin_key {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let db = &*state.db;
+    let disagreements = db
+        .lrange("verify:disagreements", 0, -1)
+        .await?
+        .into_iter()
+        .filter_map(|x| serde_json::from_str(&x).ok())
+        .collect();
+
+    Ok(Json(VerificationStatus {
+        enabled: state.config.verification.is_some(),
+        awaiting_verifier: db.zcard("verify:queue").await?,
+        disagreements,
+    })
+    .into_response())
+}